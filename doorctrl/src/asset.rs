@@ -0,0 +1,40 @@
+// Static files embedded in the firmware and served by path.
+//
+// Content-Type, Content-Encoding and ETag can't be sent until weblite's responder can add headers,
+// so an asset is just its path and body. Assets must not be stored compressed until then.
+
+/// A file embedded in the firmware.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Asset {
+    /// Request path the asset is served at.
+    pub path: &'static str,
+    pub body: &'static [u8],
+}
+
+impl Asset {
+    pub const fn new(path: &'static str, body: &'static [u8]) -> Self {
+        Self { path, body }
+    }
+}
+
+/// The asset served at `path`, if any.
+pub fn find<'a>(assets: &'a [Asset], path: &str) -> Option<&'a Asset> {
+    assets.iter().find(|asset| asset.path == path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ASSETS: [Asset; 2] = [
+        Asset::new("/", b"<html></html>"),
+        Asset::new("/favicon.ico", b"\x00\x00\x01\x00"),
+    ];
+
+    #[test]
+    fn test_find() {
+        assert_eq!(find(&ASSETS, "/").unwrap().body, b"<html></html>");
+        assert_eq!(find(&ASSETS, "/favicon.ico").unwrap(), &ASSETS[1]);
+        assert!(find(&ASSETS, "/index.html").is_none());
+    }
+}
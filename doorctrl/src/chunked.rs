@@ -0,0 +1,140 @@
+// Messages too large for a single websocket frame, sent as a series of chunks.
+//
+// Each chunk starts with a flag byte, `CHUNK_LAST` on the final chunk and `CHUNK_MORE` otherwise,
+// followed by the next part of the message. The parts are collected in a dedicated buffer until
+// the final chunk completes the message.
+
+/// Flag of a chunk with more to follow.
+pub const CHUNK_MORE: u8 = 0;
+/// Flag of the chunk that completes the message.
+pub const CHUNK_LAST: u8 = 1;
+
+pub struct ChunkAssembler<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> ChunkAssembler<N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0u8; N],
+            len: 0,
+        }
+    }
+
+    /// Add a chunk, returning the assembled message once its final chunk arrives. A message that
+    /// won't fit in the buffer, or a malformed chunk, is discarded along with anything assembled
+    /// so far.
+    pub fn push(&mut self, chunk: &[u8]) -> Result<Option<&[u8]>, &'static str> {
+        let Some((&flag, part)) = chunk.split_first() else {
+            self.reset();
+            return Err("empty chunk");
+        };
+
+        if flag != CHUNK_MORE && flag != CHUNK_LAST {
+            self.reset();
+            return Err("invalid chunk flag");
+        }
+
+        let end = self.len + part.len();
+        if end > N {
+            self.reset();
+            return Err("message too large");
+        }
+
+        self.buf[self.len..end].copy_from_slice(part);
+        self.len = end;
+
+        if flag == CHUNK_MORE {
+            return Ok(None);
+        }
+
+        let len = self.len;
+        self.len = 0;
+        Ok(Some(&self.buf[..len]))
+    }
+
+    /// Discard any partly assembled message.
+    pub fn reset(&mut self) {
+        self.len = 0;
+    }
+}
+
+impl<const N: usize> Default for ChunkAssembler<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use std::vec::Vec;
+
+    use quickcheck::QuickCheck;
+
+    use super::*;
+
+    #[test]
+    fn test_assemble() {
+        let mut assembler = ChunkAssembler::<8>::new();
+        assert_eq!(assembler.push(&[CHUNK_MORE, b'a', b'b']), Ok(None));
+        assert_eq!(assembler.push(&[CHUNK_MORE, b'c']), Ok(None));
+        assert_eq!(assembler.push(&[CHUNK_LAST, b'd']), Ok(Some(&b"abcd"[..])));
+
+        // The next message starts afresh.
+        assert_eq!(assembler.push(&[CHUNK_LAST, b'e']), Ok(Some(&b"e"[..])));
+    }
+
+    #[test]
+    fn test_too_large() {
+        let mut assembler = ChunkAssembler::<4>::new();
+        assert_eq!(assembler.push(&[CHUNK_MORE, b'a', b'b', b'c']), Ok(None));
+        assert!(assembler.push(&[CHUNK_LAST, b'd', b'e']).is_err());
+
+        // What was assembled before the overflow is discarded.
+        assert_eq!(
+            assembler.push(&[CHUNK_LAST, b'f', b'g']),
+            Ok(Some(&b"fg"[..]))
+        );
+        assert!(assembler.push(&[]).is_err());
+        assert!(assembler.push(&[2, b'a']).is_err());
+    }
+
+    // Any sequence of chunks gives the same results as a model of the assembler that collects the
+    // parts in a Vec, and never panics.
+    #[test]
+    fn test_push_any_chunks() {
+        fn prop(chunks: Vec<Vec<u8>>) -> bool {
+            let mut assembler = ChunkAssembler::<8>::new();
+            let mut model = Vec::new();
+            chunks.iter().all(|chunk| {
+                let expected = match chunk.split_first() {
+                    Some((&flag, part))
+                        if (flag == CHUNK_MORE || flag == CHUNK_LAST)
+                            && model.len() + part.len() <= 8 =>
+                    {
+                        model.extend_from_slice(part);
+                        if flag == CHUNK_LAST {
+                            Ok(Some(core::mem::take(&mut model)))
+                        } else {
+                            Ok(None)
+                        }
+                    }
+                    _ => {
+                        model.clear();
+                        Err(())
+                    }
+                };
+                let pushed = assembler
+                    .push(chunk)
+                    .map(|msg| msg.map(Vec::from))
+                    .map_err(|_| ());
+                pushed == expected
+            })
+        }
+        QuickCheck::new()
+            .tests(1000)
+            .quickcheck(prop as fn(Vec<Vec<u8>>) -> bool);
+    }
+}
@@ -0,0 +1,270 @@
+// Lock commands waiting for the door task.
+//
+// Commands carry where they came from and when they stop being worth acting on, so a command that
+// sat in the queue (e.g. while the door task was busy) isn't applied long after it was sent.
+// Nothing is dropped silently: expired commands, and commands evicted from a full queue, are logged.
+use core::cell::RefCell;
+use core::cmp::Reverse;
+
+use defmt::{info, warn};
+use embassy_sync::blocking_mutex::{Mutex, raw::RawMutex};
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant};
+use heapless::Deque;
+
+use crate::state::LockState;
+
+/// Capacity of the command queue.
+pub const COMMAND_QUEUE_LEN: usize = 4;
+
+/// How long a command stays valid after it was sent.
+pub const COMMAND_TTL: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub enum LockAction {
+    Lock,
+    Unlock,
+    /// Unlock for the configured buzz-in time, then lock again.
+    Open,
+}
+
+/// Where a command came from, in increasing priority. Commands are taken highest priority first.
+/// When the queue is full, the oldest command of the lowest priority makes way.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, defmt::Format)]
+pub enum CommandSource {
+    /// Home Assistant, usually an automation.
+    Mqtt,
+    /// Someone using the web interface.
+    Web,
+    /// A local controller such as a wall panel.
+    Udp,
+    /// The override button on the device itself.
+    Button,
+    /// Loss of mains power, putting the lock in its configured power fail state.
+    PowerFail,
+}
+
+impl LockAction {
+    /// The action that toggles the lock from `lock_state`. A lock in an unknown or jammed state is
+    /// locked.
+    pub fn toggle(lock_state: Option<LockState>) -> Self {
+        match lock_state {
+            Some(LockState::Locked | LockState::Locking) => LockAction::Unlock,
+            _ => LockAction::Lock,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Command {
+    pub action: LockAction,
+    pub source: CommandSource,
+    pub expires: Instant,
+}
+
+pub struct CommandQueue<M: RawMutex, const N: usize> {
+    queue: Mutex<M, RefCell<Deque<Command, N>>>,
+    ready: Signal<M, ()>,
+}
+
+impl<M: RawMutex, const N: usize> CommandQueue<M, N> {
+    pub const fn new() -> Self {
+        Self {
+            queue: Mutex::new(RefCell::new(Deque::new())),
+            ready: Signal::new(),
+        }
+    }
+
+    /// Queue a command sent at `now`.
+    pub fn send(&self, action: LockAction, source: CommandSource, now: Instant) {
+        let command = Command {
+            action,
+            source,
+            expires: now + COMMAND_TTL,
+        };
+
+        let queued = self.queue.lock(|queue| {
+            let mut queue = queue.borrow_mut();
+            drop_expired(&mut queue, now);
+
+            if queue.is_full() {
+                // Evict the oldest of the lowest priority commands, unless they all outrank
+                // this one.
+                let lowest = queue
+                    .iter()
+                    .enumerate()
+                    .min_by_key(|(_, queued)| queued.source)
+                    .filter(|(_, queued)| queued.source <= source)
+                    .map(|(index, _)| index);
+
+                match lowest {
+                    Some(index) => {
+                        let evicted = remove(&mut queue, index);
+                        warn!(
+                            "command queue full, dropping {} command from {}",
+                            evicted.action, evicted.source
+                        );
+                    }
+                    None => {
+                        warn!(
+                            "command queue full, dropping {} command from {}",
+                            action, source
+                        );
+                        return false;
+                    }
+                }
+            }
+
+            queue.push_back(command).is_ok()
+        });
+
+        if queued {
+            self.ready.signal(());
+        }
+    }
+
+    /// Wait until a command may be available.
+    pub async fn wait(&self) {
+        self.ready.wait().await
+    }
+
+    /// Take the highest priority command that is still valid at `now`, the oldest first if several
+    /// share that priority.
+    pub fn take(&self, now: Instant) -> Option<Command> {
+        self.queue.lock(|queue| {
+            let mut queue = queue.borrow_mut();
+            drop_expired(&mut queue, now);
+
+            let highest = queue
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, queued)| Reverse(queued.source))
+                .map(|(index, _)| index)?;
+            Some(remove(&mut queue, highest))
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.lock(|queue| queue.borrow().len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<M: RawMutex, const N: usize> Default for CommandQueue<M, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn drop_expired<const N: usize>(queue: &mut Deque<Command, N>, now: Instant) {
+    while let Some(command) = queue.front()
+        && command.expires <= now
+    {
+        info!(
+            "dropping expired {} command from {}",
+            command.action, command.source
+        );
+        queue.pop_front();
+    }
+}
+
+// Deque has no remove, so rotate the queue to take out the command at `index`, keeping the others
+// in order.
+fn remove<const N: usize>(queue: &mut Deque<Command, N>, index: usize) -> Command {
+    let mut removed = None;
+    for i in 0..queue.len() {
+        let command = queue.pop_front().unwrap();
+        if i == index {
+            removed = Some(command);
+        } else {
+            queue.push_back(command).ok();
+        }
+    }
+    removed.unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+
+    use super::*;
+
+    fn at(ms: u64) -> Instant {
+        Instant::from_millis(ms)
+    }
+
+    #[test]
+    fn test_queue_order_and_expiry() {
+        let queue = CommandQueue::<NoopRawMutex, 4>::new();
+        queue.send(LockAction::Unlock, CommandSource::Web, at(0));
+        queue.send(LockAction::Lock, CommandSource::Mqtt, at(4000));
+        assert_eq!(queue.len(), 2);
+
+        // The web command has expired by the time the queue is read.
+        let command = queue.take(at(6000)).unwrap();
+        assert_eq!(command.action, LockAction::Lock);
+        assert_eq!(command.source, CommandSource::Mqtt);
+        assert!(queue.take(at(6000)).is_none());
+    }
+
+    #[test]
+    fn test_toggle() {
+        assert_eq!(
+            LockAction::toggle(Some(LockState::Locked)),
+            LockAction::Unlock
+        );
+        assert_eq!(
+            LockAction::toggle(Some(LockState::Unlocked)),
+            LockAction::Lock
+        );
+        assert_eq!(
+            LockAction::toggle(Some(LockState::Jammed)),
+            LockAction::Lock
+        );
+        assert_eq!(LockAction::toggle(None), LockAction::Lock);
+    }
+
+    #[test]
+    fn test_queue_full() {
+        let queue = CommandQueue::<NoopRawMutex, 2>::new();
+        queue.send(LockAction::Lock, CommandSource::Web, at(0));
+        queue.send(LockAction::Unlock, CommandSource::Mqtt, at(1));
+
+        // Evicts the MQTT command.
+        queue.send(LockAction::Unlock, CommandSource::Udp, at(2));
+        // Outranked by everything queued, so dropped.
+        queue.send(LockAction::Lock, CommandSource::Mqtt, at(3));
+
+        let sources = [queue.take(at(4)), queue.take(at(4)), queue.take(at(4))]
+            .map(|command| command.map(|command| command.source));
+        assert_eq!(
+            sources,
+            [Some(CommandSource::Udp), Some(CommandSource::Web), None]
+        );
+    }
+
+    #[test]
+    fn test_take_by_priority() {
+        let queue = CommandQueue::<NoopRawMutex, 4>::new();
+        queue.send(LockAction::Lock, CommandSource::Mqtt, at(0));
+        queue.send(LockAction::Unlock, CommandSource::Web, at(1));
+        queue.send(LockAction::Open, CommandSource::Mqtt, at(2));
+
+        // The web command was queued last but outranks both MQTT commands, which follow in the
+        // order they were sent.
+        let actions = [queue.take(at(3)), queue.take(at(3)), queue.take(at(3))]
+            .map(|command| command.map(|command| command.action));
+        assert_eq!(
+            actions,
+            [
+                Some(LockAction::Unlock),
+                Some(LockAction::Lock),
+                Some(LockAction::Open)
+            ]
+        );
+        assert!(queue.is_empty());
+    }
+}
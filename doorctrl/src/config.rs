@@ -3,10 +3,38 @@ use embedded_storage::{nor_flash::NorFlash, nor_flash::ReadNorFlash};
 use serde::de::Visitor;
 use serde::{Deserialize, Serialize};
 
+use crate::distance::Calibration;
+use crate::door::{LockPolarity, LockWhileOpen, PowerFailLock};
+
+// Configurations saved before the layout was versioned hold only the fields up to mqtt_pass.
 const CONFIGV1_MAGIC: [u8; 13] = [
     b'd', b'o', b'o', b'r', b'c', b'o', b'n', b't', b'r', b'o', b'l', b'v', b'1',
 ];
 
+// The v1 fields are followed by the length of the fields added since, then those fields. New
+// fields are appended to the end, so a configuration saved before a field existed still loads,
+// with the field at its default.
+const CONFIGV2_MAGIC: [u8; 13] = [
+    b'd', b'o', b'o', b'r', b'c', b'o', b'n', b't', b'r', b'o', b'l', b'v', b'2',
+];
+
+// Length of a v1 configuration: the pre magic and six values, the MQTT port and flags, and the
+// post magic.
+const CONFIGV1_LEN: usize = 7 * 64 + 4 + 64;
+
+// Room for an encoded configuration, leaving space to append fields.
+const CONFIG_BUF_LEN: usize = 1024;
+
+// Each configuration gets a flash sector to itself.
+const CONFIG_SECTOR_LEN: u32 = 4096;
+
+/// Where the previous configuration is kept while new WiFi settings are tried, so the device can
+/// fall back to it if they don't work.
+pub const FALLBACK_OFFSET: u32 = CONFIG_SECTOR_LEN;
+
+/// End of the flash used for configuration, including the fallback.
+pub const CONFIG_FLASH_END: u32 = FALLBACK_OFFSET + CONFIG_SECTOR_LEN;
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct ConfigV1Value([u8; 64]);
 
@@ -107,7 +135,7 @@ impl Default for ConfigV1Value {
     }
 }
 
-#[derive(Clone, Copy, Serialize, Debug)]
+#[derive(Clone, Copy, Serialize, Debug, PartialEq)]
 pub struct ConfigV1 {
     #[serde(skip)]
     pre_magic: ConfigV1Value,
@@ -122,6 +150,27 @@ pub struct ConfigV1 {
     pub mqtt_user: ConfigV1Value,
     #[serde(skip_serializing)]
     pub mqtt_pass: ConfigV1Value,
+    pub health_wifi: bool,
+    pub health_mqtt: bool,
+    #[serde(skip_serializing)]
+    pub udp_key: ConfigV1Value,
+    pub door_open_mm: u16,
+    pub door_closed_mm: u16,
+    pub vehicle_threshold_mm: u16,
+    pub position_interval_ms: u16,
+    pub position_delta: u8,
+    pub vehicle_interval_ms: u16,
+    pub lock_while_open: LockWhileOpen,
+    pub visitor_led: bool,
+    pub buzz_in_secs: u8,
+    pub lock_polarity: LockPolarity,
+    pub startup_grace_ms: u16,
+    pub http_max_connections: u8,
+    pub lock_name: ConfigV1Value,
+    pub door_name: ConfigV1Value,
+    pub override_hold_ms: u16,
+    pub power_fail_lock: PowerFailLock,
+    pub rssi_interval_secs: u16,
     #[serde(skip)]
     pub post_magic: ConfigV1Value,
 }
@@ -129,7 +178,7 @@ pub struct ConfigV1 {
 impl Default for ConfigV1 {
     fn default() -> Self {
         let mut magic = ConfigV1Value([0u8; 64]);
-        magic.0[..CONFIGV1_MAGIC.len()].copy_from_slice(&CONFIGV1_MAGIC);
+        magic.0[..CONFIGV2_MAGIC.len()].copy_from_slice(&CONFIGV2_MAGIC);
 
         Self {
             pre_magic: magic,
@@ -142,6 +191,26 @@ impl Default for ConfigV1 {
             mqtt_tls_verify_cert: true,
             mqtt_user: ConfigV1Value::default(),
             mqtt_pass: ConfigV1Value::default(),
+            health_wifi: true,
+            health_mqtt: true,
+            udp_key: ConfigV1Value::default(),
+            door_open_mm: 0,
+            door_closed_mm: 0,
+            vehicle_threshold_mm: 1500,
+            position_interval_ms: 250,
+            position_delta: 2,
+            vehicle_interval_ms: 1000,
+            lock_while_open: LockWhileOpen::Immediate,
+            visitor_led: false,
+            buzz_in_secs: 5,
+            lock_polarity: LockPolarity::ActiveHigh,
+            startup_grace_ms: 1000,
+            http_max_connections: 4,
+            lock_name: ConfigV1Value::default(),
+            door_name: ConfigV1Value::default(),
+            override_hold_ms: 2000,
+            power_fail_lock: PowerFailLock::Leave,
+            rssi_interval_secs: 30,
             post_magic: magic,
         }
     }
@@ -194,38 +263,200 @@ impl ConfigV1 {
         {
             self.mqtt_pass = value;
         }
+
+        if let Some(value) = update.health_wifi {
+            self.health_wifi = value;
+        }
+
+        if let Some(value) = update.health_mqtt {
+            self.health_mqtt = value;
+        }
+
+        // The key is never sent to clients, so it is only in an update when being changed. An
+        // empty key turns UDP control off.
+        if let Some(value) = update.udp_key {
+            self.udp_key = value;
+        }
+
+        if let Some(value) = update.vehicle_threshold_mm
+            && value != 0
+        {
+            self.vehicle_threshold_mm = value;
+        }
+
+        // An interval of 0 turns the sensor's reporting off.
+        if let Some(value) = update.position_interval_ms {
+            self.position_interval_ms = value;
+        }
+
+        if let Some(value) = update.position_delta {
+            self.position_delta = value;
+        }
+
+        if let Some(value) = update.vehicle_interval_ms {
+            self.vehicle_interval_ms = value;
+        }
+
+        if let Some(value) = update.lock_while_open {
+            self.lock_while_open = value;
+        }
+
+        if let Some(value) = update.visitor_led {
+            self.visitor_led = value;
+        }
+
+        if let Some(value) = update.buzz_in_secs
+            && value != 0
+        {
+            self.buzz_in_secs = value;
+        }
+
+        if let Some(value) = update.lock_polarity {
+            self.lock_polarity = value;
+        }
+
+        // A grace period of 0 publishes state as soon as the door task starts.
+        if let Some(value) = update.startup_grace_ms {
+            self.startup_grace_ms = value;
+        }
+
+        if let Some(value) = update.http_max_connections
+            && value != 0
+        {
+            self.http_max_connections = value;
+        }
+
+        // An empty name puts back the default name for the entity.
+        if let Some(value) = update.lock_name {
+            self.lock_name = value;
+        }
+
+        if let Some(value) = update.door_name {
+            self.door_name = value;
+        }
+
+        if let Some(value) = update.override_hold_ms
+            && value != 0
+        {
+            self.override_hold_ms = value;
+        }
+
+        if let Some(value) = update.power_fail_lock {
+            self.power_fail_lock = value;
+        }
+
+        // An interval of 0 stops sampling the WiFi signal strength.
+        if let Some(value) = update.rssi_interval_secs {
+            self.rssi_interval_secs = value;
+        }
+    }
+
+    /// Whether changing the configuration from `previous` to this one needs a restart to take
+    /// effect. Only the friendly names can be applied while running.
+    pub fn needs_restart(&self, previous: &ConfigV1) -> bool {
+        let mut live = *previous;
+        live.lock_name = self.lock_name;
+        live.door_name = self.door_name;
+
+        live != *self
+    }
+
+    pub fn calibration(&self) -> Calibration {
+        Calibration {
+            open_mm: self.door_open_mm,
+            closed_mm: self.door_closed_mm,
+        }
+    }
+
+    /// Evaluate the configured health criteria against the current connectivity.
+    pub fn healthy(&self, wifi_connected: bool, mqtt_connected: bool) -> bool {
+        if self.health_wifi && !wifi_connected {
+            return false;
+        }
+        if self.health_mqtt && !mqtt_connected {
+            return false;
+        }
+
+        true
+    }
+
+    /// Whether moving from `previous` to this configuration changes the WiFi network, so
+    /// `previous` should be kept to fall back to. Only a complete configuration can be fallen back
+    /// to.
+    pub fn needs_fallback(&self, previous: &ConfigV1) -> bool {
+        previous.complete()
+            && (self.wifi_ssid != previous.wifi_ssid || self.wifi_pass != previous.wifi_pass)
     }
 
     pub fn load<S: ReadNorFlash>(src: &mut S) -> Result<Self, &'static str> {
-        let mut read_buf = [0u8; size_of::<ConfigV1>()];
-        if src.read(0, &mut read_buf[..]).is_err() {
+        Self::load_at(src, 0)
+    }
+
+    pub fn save<S: NorFlash>(&self, mut dst: S) -> Result<(), &'static str> {
+        self.save_at(&mut dst, 0)
+    }
+
+    /// Load the configuration kept to fall back to, if there is one.
+    pub fn load_fallback<S: ReadNorFlash>(src: &mut S) -> Result<Self, &'static str> {
+        Self::load_at(src, FALLBACK_OFFSET)
+    }
+
+    /// Keep this configuration to fall back to while new WiFi settings are tried.
+    pub fn save_fallback<S: NorFlash>(&self, mut dst: S) -> Result<(), &'static str> {
+        self.save_at(&mut dst, FALLBACK_OFFSET)
+    }
+
+    /// Forget the configuration kept to fall back to, once the new settings have worked.
+    pub fn discard_fallback<S: NorFlash>(mut dst: S) -> Result<(), &'static str> {
+        if dst
+            .erase(FALLBACK_OFFSET, FALLBACK_OFFSET + CONFIG_SECTOR_LEN)
+            .is_err()
+        {
+            return Err("error erasing fallback config");
+        }
+
+        Ok(())
+    }
+
+    /// Make the configuration kept to fall back to current again, returning it.
+    pub fn revert_to_fallback<S: NorFlash>(dst: &mut S) -> Result<Self, &'static str> {
+        let fallback = Self::load_fallback(dst)?;
+        fallback.save_at(dst, 0)?;
+        Self::discard_fallback(dst)?;
+
+        Ok(fallback)
+    }
+
+    fn load_at<S: ReadNorFlash>(src: &mut S, offset: u32) -> Result<Self, &'static str> {
+        let mut read_buf = [0u8; CONFIG_BUF_LEN];
+        if src.read(offset, &mut read_buf[..]).is_err() {
             return Err("error reading config from storage");
         }
 
         Self::decode(&read_buf)
     }
 
-    pub fn save<S: NorFlash>(&self, mut dst: S) -> Result<(), &'static str> {
+    fn save_at<S: NorFlash>(&self, dst: &mut S, offset: u32) -> Result<(), &'static str> {
         if !self.complete() {
             return Err("config not complete");
         }
 
-        let mut write_buf = [0u8; size_of::<ConfigV1>()];
-        self.encode(&mut write_buf).unwrap();
+        let mut write_buf = [0u8; CONFIG_BUF_LEN];
+        self.encode(&mut write_buf)?;
 
-        let erase_len: u32 = 4096;
-        if dst.erase(0, erase_len).is_err() {
+        if dst.erase(offset, offset + CONFIG_SECTOR_LEN).is_err() {
             return Err("error erasing flash prior to write");
         }
-        if dst.write(0, &write_buf).is_err() {
+        if dst.write(offset, &write_buf).is_err() {
             return Err("error writing to storage");
         }
 
         Ok(())
     }
 
-    fn encode(&self, buf: &mut [u8]) -> Result<(), &'static str> {
-        if buf.len() < size_of::<ConfigV1>() {
+    /// Encode the configuration into `buf`, returning the length written.
+    fn encode(&self, buf: &mut [u8]) -> Result<usize, &'static str> {
+        if buf.len() < CONFIG_BUF_LEN {
             return Err("buffer to small to store config");
         }
 
@@ -262,23 +493,104 @@ impl ConfigV1 {
         buf[offset..offset + 64].copy_from_slice(&self.mqtt_pass.0);
         offset += 64;
 
+        // The length of the fields added since v1 is filled in once they are written.
+        let added_len_offset = offset;
+        offset += 2;
+
+        buf[offset] = self.health_wifi as u8;
+        offset += 1;
+
+        buf[offset] = self.health_mqtt as u8;
+        offset += 1;
+
+        buf[offset..offset + 64].copy_from_slice(&self.udp_key.0);
+        offset += 64;
+
+        buf[offset..offset + size_of_val(&self.door_open_mm)]
+            .copy_from_slice(&self.door_open_mm.to_be_bytes());
+        offset += size_of_val(&self.door_open_mm);
+
+        buf[offset..offset + size_of_val(&self.door_closed_mm)]
+            .copy_from_slice(&self.door_closed_mm.to_be_bytes());
+        offset += size_of_val(&self.door_closed_mm);
+
+        buf[offset..offset + size_of_val(&self.vehicle_threshold_mm)]
+            .copy_from_slice(&self.vehicle_threshold_mm.to_be_bytes());
+        offset += size_of_val(&self.vehicle_threshold_mm);
+
+        buf[offset..offset + size_of_val(&self.position_interval_ms)]
+            .copy_from_slice(&self.position_interval_ms.to_be_bytes());
+        offset += size_of_val(&self.position_interval_ms);
+
+        buf[offset] = self.position_delta;
+        offset += 1;
+
+        buf[offset..offset + size_of_val(&self.vehicle_interval_ms)]
+            .copy_from_slice(&self.vehicle_interval_ms.to_be_bytes());
+        offset += size_of_val(&self.vehicle_interval_ms);
+
+        buf[offset] = self.lock_while_open.into();
+        offset += 1;
+
+        buf[offset] = self.visitor_led as u8;
+        offset += 1;
+
+        buf[offset] = self.buzz_in_secs;
+        offset += 1;
+
+        buf[offset] = self.lock_polarity.into();
+        offset += 1;
+
+        buf[offset..offset + size_of_val(&self.startup_grace_ms)]
+            .copy_from_slice(&self.startup_grace_ms.to_be_bytes());
+        offset += size_of_val(&self.startup_grace_ms);
+
+        buf[offset] = self.http_max_connections;
+        offset += 1;
+
+        buf[offset..offset + 64].copy_from_slice(&self.lock_name.0);
+        offset += 64;
+
+        buf[offset..offset + 64].copy_from_slice(&self.door_name.0);
+        offset += 64;
+
+        buf[offset..offset + size_of_val(&self.override_hold_ms)]
+            .copy_from_slice(&self.override_hold_ms.to_be_bytes());
+        offset += size_of_val(&self.override_hold_ms);
+
+        buf[offset] = self.power_fail_lock.into();
+        offset += 1;
+
+        buf[offset..offset + size_of_val(&self.rssi_interval_secs)]
+            .copy_from_slice(&self.rssi_interval_secs.to_be_bytes());
+        offset += size_of_val(&self.rssi_interval_secs);
+
+        let added_len = (offset - added_len_offset - 2) as u16;
+        buf[added_len_offset..added_len_offset + 2].copy_from_slice(&added_len.to_be_bytes());
+
         buf[offset..offset + 64].copy_from_slice(&self.post_magic.0);
-        Ok(())
+        offset += 64;
+
+        Ok(offset)
     }
 
     fn decode(buf: &[u8]) -> Result<Self, &'static str> {
-        if buf.len() < size_of::<ConfigV1>() {
+        if buf.len() < CONFIGV1_LEN {
             return Err("buffer to small to contain config");
         }
 
+        let v1 = if buf[..CONFIGV2_MAGIC.len()] == CONFIGV2_MAGIC[..] {
+            false
+        } else if buf[..CONFIGV1_MAGIC.len()] == CONFIGV1_MAGIC[..] {
+            true
+        } else {
+            return Err("no config exists or config corrupt");
+        };
+
+        // The magics are left at their defaults, so a v1 configuration is saved again as v2.
         let mut config = ConfigV1::default();
 
-        let mut offset = 0;
-        config
-            .pre_magic
-            .0
-            .copy_from_slice(&buf[offset..offset + 64]);
-        offset += 64;
+        let mut offset = 64;
         config
             .device_name
             .0
@@ -320,19 +632,48 @@ impl ConfigV1 {
             .0
             .copy_from_slice(&buf[offset..offset + 64]);
         offset += 64;
-        config
-            .post_magic
-            .0
-            .copy_from_slice(&buf[offset..offset + 64]);
 
-        if config.pre_magic.0[..CONFIGV1_MAGIC.len()] != CONFIGV1_MAGIC[..] {
-            return Err("no config exists or config corrupt");
+        if v1 {
+            if buf[offset..offset + CONFIGV1_MAGIC.len()] != CONFIGV1_MAGIC[..] {
+                return Err("config corrupt");
+            }
+            return Ok(config);
         }
 
-        if config.post_magic.0[..CONFIGV1_MAGIC.len()] != CONFIGV1_MAGIC[..] {
+        let added_len =
+            u16::from_be_bytes(TryInto::<[u8; 2]>::try_into(&buf[offset..offset + 2]).unwrap());
+        offset += 2;
+
+        let end = offset + added_len as usize;
+        if buf.len() < end + 64 || buf[end..end + CONFIGV2_MAGIC.len()] != CONFIGV2_MAGIC[..] {
             return Err("config corrupt");
         }
 
+        let mut added = AddedFields {
+            buf: &buf[..end],
+            offset,
+        };
+        config.health_wifi = added.bool().unwrap_or(config.health_wifi);
+        config.health_mqtt = added.bool().unwrap_or(config.health_mqtt);
+        config.udp_key = added.value().unwrap_or(config.udp_key);
+        config.door_open_mm = added.u16().unwrap_or(config.door_open_mm);
+        config.door_closed_mm = added.u16().unwrap_or(config.door_closed_mm);
+        config.vehicle_threshold_mm = added.u16().unwrap_or(config.vehicle_threshold_mm);
+        config.position_interval_ms = added.u16().unwrap_or(config.position_interval_ms);
+        config.position_delta = added.u8().unwrap_or(config.position_delta);
+        config.vehicle_interval_ms = added.u16().unwrap_or(config.vehicle_interval_ms);
+        config.lock_while_open = added.u8().map_or(config.lock_while_open, Into::into);
+        config.visitor_led = added.bool().unwrap_or(config.visitor_led);
+        config.buzz_in_secs = added.u8().unwrap_or(config.buzz_in_secs);
+        config.lock_polarity = added.u8().map_or(config.lock_polarity, Into::into);
+        config.startup_grace_ms = added.u16().unwrap_or(config.startup_grace_ms);
+        config.http_max_connections = added.u8().unwrap_or(config.http_max_connections);
+        config.lock_name = added.value().unwrap_or(config.lock_name);
+        config.door_name = added.value().unwrap_or(config.door_name);
+        config.override_hold_ms = added.u16().unwrap_or(config.override_hold_ms);
+        config.power_fail_lock = added.u8().map_or(config.power_fail_lock, Into::into);
+        config.rssi_interval_secs = added.u16().unwrap_or(config.rssi_interval_secs);
+
         Ok(config)
     }
 
@@ -360,7 +701,42 @@ impl ConfigV1 {
     }
 }
 
-#[derive(Deserialize)]
+// Reads the fields added since v1 in the order they were appended. A configuration saved before a
+// field was added ends before it, so reading the field gives None.
+struct AddedFields<'a> {
+    buf: &'a [u8],
+    offset: usize,
+}
+
+impl AddedFields<'_> {
+    fn take<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let bytes = self
+            .buf
+            .get(self.offset..self.offset + N)?
+            .try_into()
+            .ok()?;
+        self.offset += N;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take().map(|[byte]: [u8; 1]| byte)
+    }
+
+    fn bool(&mut self) -> Option<bool> {
+        self.u8().map(|byte| byte == 1)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take().map(u16::from_be_bytes)
+    }
+
+    fn value(&mut self) -> Option<ConfigV1Value> {
+        self.take().map(ConfigV1Value)
+    }
+}
+
+#[derive(Default, Deserialize)]
 pub struct ConfigV1Update {
     device_name: Option<ConfigV1Value>,
     wifi_ssid: Option<ConfigV1Value>,
@@ -370,6 +746,24 @@ pub struct ConfigV1Update {
     mqtt_tls: Option<bool>,
     mqtt_user: Option<ConfigV1Value>,
     mqtt_pass: Option<ConfigV1Value>,
+    health_wifi: Option<bool>,
+    health_mqtt: Option<bool>,
+    udp_key: Option<ConfigV1Value>,
+    vehicle_threshold_mm: Option<u16>,
+    position_interval_ms: Option<u16>,
+    position_delta: Option<u8>,
+    vehicle_interval_ms: Option<u16>,
+    lock_while_open: Option<LockWhileOpen>,
+    visitor_led: Option<bool>,
+    buzz_in_secs: Option<u8>,
+    lock_polarity: Option<LockPolarity>,
+    startup_grace_ms: Option<u16>,
+    http_max_connections: Option<u8>,
+    lock_name: Option<ConfigV1Value>,
+    door_name: Option<ConfigV1Value>,
+    override_hold_ms: Option<u16>,
+    power_fail_lock: Option<PowerFailLock>,
+    rssi_interval_secs: Option<u16>,
 }
 
 #[cfg(test)]
@@ -421,6 +815,10 @@ mod tests {
             "mydoor",
             "device name should be 'mydoor'"
         );
+
+        config.udp_key = "secret".try_into().unwrap();
+        config.update(&from_str::<ConfigV1Update>("{\"udp_key\": \"\"}").unwrap().0);
+        assert_eq!(config.udp_key.as_str(), "", "empty key turns UDP off");
     }
 
     #[test]
@@ -433,7 +831,7 @@ mod tests {
         match to_slice(&config, &mut serialized[..]) {
             Ok(n) => assert_eq!(
                 str::from_utf8(&serialized[..n]).unwrap_or("not_utf8"),
-                "{\"device_name\":\"mydevice\",\"wifi_ssid\":\"\",\"mqtt_host\":\"\",\"mqtt_port\":1883,\"mqtt_tls\":false,\"mqtt_tls_verify_cert\":true,\"mqtt_user\":\"\"}",
+                "{\"device_name\":\"mydevice\",\"wifi_ssid\":\"\",\"mqtt_host\":\"\",\"mqtt_port\":1883,\"mqtt_tls\":false,\"mqtt_tls_verify_cert\":true,\"mqtt_user\":\"\",\"health_wifi\":true,\"health_mqtt\":true,\"door_open_mm\":0,\"door_closed_mm\":0,\"vehicle_threshold_mm\":1500,\"position_interval_ms\":250,\"position_delta\":2,\"vehicle_interval_ms\":1000,\"lock_while_open\":\"immediate\",\"visitor_led\":false,\"buzz_in_secs\":5,\"lock_polarity\":\"active_high\",\"startup_grace_ms\":1000,\"http_max_connections\":4,\"lock_name\":\"\",\"door_name\":\"\",\"override_hold_ms\":2000,\"power_fail_lock\":\"leave\",\"rssi_interval_secs\":30}",
             ),
             Err(e) => assert!(false, "serialization returned error: {}", e),
         }
@@ -446,17 +844,32 @@ mod tests {
         config.mqtt_port = 1024;
         config.mqtt_tls = true;
         config.mqtt_tls_verify_cert = false;
-
-        let mut outbuf = [0u8; size_of::<ConfigV1>()];
-        if let Err(e) = config.encode(&mut outbuf) {
-            panic!("{}", e);
-        }
-
-        let outhex = encode(&outbuf);
+        config.health_mqtt = false;
+        config.door_open_mm = 500;
+        config.door_closed_mm = 2500;
+        config.vehicle_interval_ms = 0;
+        config.lock_while_open = LockWhileOpen::Reject;
+        config.visitor_led = true;
+        config.buzz_in_secs = 8;
+        config.lock_polarity = LockPolarity::ActiveLow;
+        config.startup_grace_ms = 2000;
+        config.http_max_connections = 2;
+        config.lock_name = "Front Door Deadbolt".try_into().unwrap();
+        config.override_hold_ms = 3000;
+        config.power_fail_lock = PowerFailLock::Lock;
+        config.rssi_interval_secs = 0;
+
+        let mut outbuf = [0u8; CONFIG_BUF_LEN];
+        let len = match config.encode(&mut outbuf) {
+            Ok(len) => len,
+            Err(e) => panic!("{}", e),
+        };
+
+        let outhex = encode(&outbuf[..len]);
 
         assert_eq!(
             outhex,
-            "646f6f72636f6e74726f6c7631000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000\
+            "646f6f72636f6e74726f6c7632000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000\
              61616161616100000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000\
              00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000\
              00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000\
@@ -466,7 +879,28 @@ mod tests {
              00\
              00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000\
              00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000\
-             646f6f72636f6e74726f6c7631000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000"
+             00d9\
+             01\
+             00\
+             00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000\
+             01f4\
+             09c4\
+             05dc\
+             00fa\
+             02\
+             0000\
+             02\
+             01\
+             08\
+             01\
+             07d0\
+             02\
+             46726f6e7420446f6f722044656164626f6c74000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000\
+             00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000\
+             0bb8\
+             01\
+             0000\
+             646f6f72636f6e74726f6c7632000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000"
         );
 
         let inbuf = decode(outhex).expect("invalid hex decode input");
@@ -476,5 +910,111 @@ mod tests {
         assert_eq!(in_config.mqtt_port, config.mqtt_port);
         assert_eq!(in_config.mqtt_tls, config.mqtt_tls);
         assert_eq!(in_config.mqtt_tls_verify_cert, config.mqtt_tls_verify_cert);
+        assert_eq!(in_config.health_wifi, config.health_wifi);
+        assert_eq!(in_config.health_mqtt, config.health_mqtt);
+        assert_eq!(in_config.calibration(), config.calibration());
+        assert_eq!(in_config.vehicle_threshold_mm, config.vehicle_threshold_mm);
+        assert_eq!(in_config.position_interval_ms, config.position_interval_ms);
+        assert_eq!(in_config.position_delta, config.position_delta);
+        assert_eq!(in_config.vehicle_interval_ms, config.vehicle_interval_ms);
+        assert_eq!(in_config.lock_while_open, config.lock_while_open);
+        assert_eq!(in_config.visitor_led, config.visitor_led);
+        assert_eq!(in_config.buzz_in_secs, config.buzz_in_secs);
+        assert_eq!(in_config.lock_polarity, config.lock_polarity);
+        assert_eq!(in_config.startup_grace_ms, config.startup_grace_ms);
+        assert_eq!(in_config.http_max_connections, config.http_max_connections);
+        assert_eq!(in_config.lock_name, config.lock_name);
+        assert_eq!(in_config.door_name, config.door_name);
+        assert_eq!(in_config.override_hold_ms, config.override_hold_ms);
+        assert_eq!(in_config.power_fail_lock, config.power_fail_lock);
+        assert_eq!(in_config.rssi_interval_secs, config.rssi_interval_secs);
+    }
+
+    #[test]
+    fn test_decode_older_layouts() {
+        let mut config = ConfigV1::default();
+        config.device_name = "door".try_into().unwrap();
+        config.mqtt_port = 8883;
+        config.health_mqtt = false;
+        config.buzz_in_secs = 8;
+
+        let mut buf = [0u8; CONFIG_BUF_LEN];
+        config.encode(&mut buf).unwrap();
+
+        // Saved before the layout was versioned: just the v1 fields between v1 magics.
+        let v1_fields = CONFIGV1_LEN - 64;
+        let mut v1 = [0u8; CONFIG_BUF_LEN];
+        v1[..v1_fields].copy_from_slice(&buf[..v1_fields]);
+        v1[..CONFIGV1_MAGIC.len()].copy_from_slice(&CONFIGV1_MAGIC);
+        v1[v1_fields..v1_fields + CONFIGV1_MAGIC.len()].copy_from_slice(&CONFIGV1_MAGIC);
+
+        let decoded = ConfigV1::decode(&v1).unwrap();
+        assert_eq!(decoded.device_name, config.device_name);
+        assert_eq!(decoded.mqtt_port, 8883);
+        assert!(decoded.health_mqtt, "added fields take their defaults");
+        assert_eq!(decoded.buzz_in_secs, 5);
+        assert_eq!(decoded.rssi_interval_secs, 30);
+
+        // Saved when only the health criteria had been added.
+        let mut v2 = [0u8; CONFIG_BUF_LEN];
+        v2[..v1_fields + 4].copy_from_slice(&buf[..v1_fields + 4]);
+        v2[v1_fields..v1_fields + 2].copy_from_slice(&2u16.to_be_bytes());
+        v2[v1_fields + 4..v1_fields + 4 + CONFIGV2_MAGIC.len()].copy_from_slice(&CONFIGV2_MAGIC);
+
+        let decoded = ConfigV1::decode(&v2).unwrap();
+        assert!(!decoded.health_mqtt);
+        assert_eq!(decoded.buzz_in_secs, 5);
+
+        v2[v1_fields + 4] = 0;
+        assert!(ConfigV1::decode(&v2).is_err(), "post magic missing");
+    }
+
+    #[test]
+    fn test_needs_restart() {
+        let previous = ConfigV1::default();
+        let mut config = previous;
+        assert!(!config.needs_restart(&previous));
+
+        config.lock_name = "Front Door Deadbolt".try_into().unwrap();
+        assert!(!config.needs_restart(&previous), "names apply live");
+
+        config.buzz_in_secs = 10;
+        assert!(config.needs_restart(&previous));
+    }
+
+    #[test]
+    fn test_needs_fallback() {
+        let mut previous = ConfigV1::default();
+        previous.device_name = "door".try_into().unwrap();
+        previous.wifi_ssid = "home".try_into().unwrap();
+        previous.wifi_pass = "secret".try_into().unwrap();
+        previous.mqtt_host = "10.0.0.2".try_into().unwrap();
+        previous.mqtt_pass = "secret".try_into().unwrap();
+
+        let mut config = previous;
+        config.buzz_in_secs = 10;
+        assert!(!config.needs_fallback(&previous));
+
+        config.wifi_ssid = "garage".try_into().unwrap();
+        assert!(config.needs_fallback(&previous));
+        assert!(
+            !config.needs_fallback(&ConfigV1::default()),
+            "nothing to fall back to in setup"
+        );
+    }
+
+    #[test]
+    fn test_healthy() {
+        let mut config = ConfigV1::default();
+        assert!(config.healthy(true, true));
+        assert!(!config.healthy(true, false), "mqtt required by default");
+        assert!(!config.healthy(false, true), "wifi required by default");
+
+        config.health_mqtt = false;
+        assert!(config.healthy(true, false));
+        assert!(!config.healthy(false, false));
+
+        config.health_wifi = false;
+        assert!(config.healthy(false, false));
     }
 }
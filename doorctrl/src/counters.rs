@@ -0,0 +1,139 @@
+// Counters that outlive a restart without a flash write every time they change.
+//
+// RTC fast memory keeps its contents through software and watchdog resets, but not through loss of
+// power. The counters are mirrored there as they change and only written to flash now and then, so
+// a restart loses nothing and a power cut loses at most the changes since the last sync.
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+
+use crate::config::CONFIG_FLASH_END;
+
+/// Where the counters are kept in flash, after the configuration so a factory reset keeps them.
+pub const COUNTERS_OFFSET: u32 = CONFIG_FLASH_END;
+const COUNTERS_SECTOR_LEN: u32 = 4096;
+
+/// Words of RTC memory (and flash) the counters take up.
+pub const COUNTERS_WORDS: usize = 4;
+
+// Marks words holding counters rather than RTC memory's contents after power up, or erased flash.
+const COUNTERS_MAGIC: u32 = 0x636e_7472;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Counters {
+    /// Times the device has started.
+    pub boot_count: u32,
+    /// Number of the last state change published, so the numbering carries on across restarts.
+    pub event_seq: u32,
+}
+
+impl Counters {
+    /// Counters for this boot: from RTC memory if they survived the reset, otherwise the last ones
+    /// synced to flash.
+    pub fn restore(rtc: [u32; COUNTERS_WORDS], flash: Option<Counters>) -> Self {
+        let mut counters = Self::from_words(rtc).or(flash).unwrap_or_default();
+        counters.boot_count = counters.boot_count.wrapping_add(1);
+        counters
+    }
+
+    pub fn from_words(words: [u32; COUNTERS_WORDS]) -> Option<Self> {
+        let [magic, boot_count, event_seq, check] = words;
+        if magic != COUNTERS_MAGIC || check != checksum(boot_count, event_seq) {
+            return None;
+        }
+
+        Some(Self {
+            boot_count,
+            event_seq,
+        })
+    }
+
+    pub fn to_words(&self) -> [u32; COUNTERS_WORDS] {
+        [
+            COUNTERS_MAGIC,
+            self.boot_count,
+            self.event_seq,
+            checksum(self.boot_count, self.event_seq),
+        ]
+    }
+
+    pub fn load<S: ReadNorFlash>(mut src: S) -> Result<Self, &'static str> {
+        let mut buf = [0u8; COUNTERS_WORDS * 4];
+        if src.read(COUNTERS_OFFSET, &mut buf).is_err() {
+            return Err("error reading counters from storage");
+        }
+
+        let mut words = [0u32; COUNTERS_WORDS];
+        for (i, word) in words.iter_mut().enumerate() {
+            *word = u32::from_be_bytes(buf[i * 4..i * 4 + 4].try_into().unwrap());
+        }
+
+        Self::from_words(words).ok_or("no counters saved")
+    }
+
+    pub fn save<S: NorFlash>(&self, mut dst: S) -> Result<(), &'static str> {
+        let mut buf = [0u8; COUNTERS_WORDS * 4];
+        for (i, word) in self.to_words().into_iter().enumerate() {
+            buf[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+        }
+
+        if dst
+            .erase(COUNTERS_OFFSET, COUNTERS_OFFSET + COUNTERS_SECTOR_LEN)
+            .is_err()
+        {
+            return Err("error erasing flash prior to writing counters");
+        }
+        if dst.write(COUNTERS_OFFSET, &buf).is_err() {
+            return Err("error writing counters to storage");
+        }
+
+        Ok(())
+    }
+}
+
+fn checksum(boot_count: u32, event_seq: u32) -> u32 {
+    !(COUNTERS_MAGIC ^ boot_count ^ event_seq.rotate_left(16))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_restore() {
+        let rtc = Counters {
+            boot_count: 7,
+            event_seq: 120,
+        };
+        let flash = Counters {
+            boot_count: 5,
+            event_seq: 90,
+        };
+
+        assert_eq!(
+            Counters::restore(rtc.to_words(), Some(flash)),
+            Counters {
+                boot_count: 8,
+                event_seq: 120,
+            },
+            "RTC memory survived a restart"
+        );
+        assert_eq!(
+            Counters::restore([0xdead_beef; COUNTERS_WORDS], Some(flash)),
+            Counters {
+                boot_count: 6,
+                event_seq: 90,
+            },
+            "power was lost"
+        );
+        assert_eq!(
+            Counters::restore([0xffff_ffff; COUNTERS_WORDS], None),
+            Counters {
+                boot_count: 1,
+                event_seq: 0,
+            }
+        );
+
+        let mut words = rtc.to_words();
+        words[2] ^= 1;
+        assert_eq!(Counters::from_words(words), None);
+    }
+}
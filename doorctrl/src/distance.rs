@@ -0,0 +1,176 @@
+// Door position estimation from a distance sensor mounted to look at the door (e.g. an HC-SR04
+// on the garage ceiling). Readings are median filtered and mapped to a percentage open using the
+// distances recorded at the open and closed positions.
+
+/// Number of readings the median filter considers.
+pub const FILTER_LEN: usize = 5;
+
+/// Convert an HC-SR04 echo pulse width to a distance, assuming sound travels at 343 m/s.
+pub fn echo_to_mm(echo_us: u32) -> u16 {
+    // The echo covers the distance there and back.
+    (echo_us.saturating_mul(343) / 2000).min(u16::MAX as u32) as u16
+}
+
+/// Median of the most recent readings. Ultrasonic sensors occasionally report a wildly wrong
+/// distance, usually from a stray echo, which a median ignores where an average would not.
+#[derive(Default)]
+pub struct DistanceFilter {
+    readings: [u16; FILTER_LEN],
+    len: usize,
+    next: usize,
+}
+
+impl DistanceFilter {
+    /// Add a reading and return the filtered distance.
+    pub fn push(&mut self, distance_mm: u16) -> u16 {
+        self.readings[self.next] = distance_mm;
+        self.next = (self.next + 1) % FILTER_LEN;
+        self.len = (self.len + 1).min(FILTER_LEN);
+
+        let mut sorted = self.readings;
+        sorted[..self.len].sort_unstable();
+        sorted[self.len / 2]
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Calibration {
+    pub open_mm: u16,
+    pub closed_mm: u16,
+}
+
+impl Calibration {
+    /// Percentage open for a distance, or `None` until both positions have been calibrated.
+    pub fn position(&self, distance_mm: u16) -> Option<u8> {
+        if self.open_mm == 0 || self.closed_mm == 0 || self.open_mm == self.closed_mm {
+            return None;
+        }
+
+        let travel = self.open_mm as i32 - self.closed_mm as i32;
+        let moved = distance_mm as i32 - self.closed_mm as i32;
+        Some((moved * 100 / travel).clamp(0, 100) as u8)
+    }
+}
+
+/// Consecutive readings needed before presence changes, so someone walking past the sensor doesn't
+/// register as a vehicle arriving or leaving.
+pub const PRESENCE_DEBOUNCE: u8 = 8;
+
+/// Decides whether something (e.g. a car) is within a threshold distance of the sensor.
+pub struct PresenceDetector {
+    threshold_mm: u16,
+    present: Option<bool>,
+    pending: u8,
+}
+
+impl PresenceDetector {
+    pub fn new(threshold_mm: u16) -> Self {
+        Self {
+            threshold_mm,
+            present: None,
+            pending: 0,
+        }
+    }
+
+    /// Add a reading, returning the new presence when it changes.
+    pub fn update(&mut self, distance_mm: u16) -> Option<bool> {
+        let present = distance_mm < self.threshold_mm;
+        if self.present == Some(present) {
+            self.pending = 0;
+            return None;
+        }
+
+        self.pending += 1;
+        if self.present.is_some() && self.pending < PRESENCE_DEBOUNCE {
+            return None;
+        }
+
+        self.present = Some(present);
+        self.pending = 0;
+        Some(present)
+    }
+}
+
+/// Whether a new position should be reported given the last one reported and the minimum change
+/// (`delta`) worth reporting.
+pub fn position_changed(last: Option<u8>, position: u8, delta: u8) -> bool {
+    match last {
+        None => true,
+        // Always report reaching either end so the door never appears stuck at 1% or 99%.
+        Some(last) if last != position && (position == 0 || position == 100) => true,
+        Some(last) => last.abs_diff(position) >= delta.max(1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_echo_to_mm() {
+        assert_eq!(echo_to_mm(0), 0);
+        assert_eq!(echo_to_mm(5831), 1000);
+        assert_eq!(echo_to_mm(u32::MAX), u16::MAX);
+    }
+
+    #[test]
+    fn test_filter() {
+        let mut filter = DistanceFilter::default();
+        assert_eq!(filter.push(1000), 1000);
+        assert_eq!(filter.push(1010), 1010);
+        assert_eq!(filter.push(4000), 1010, "spike ignored");
+        assert_eq!(filter.push(1005), 1010);
+        assert_eq!(filter.push(1002), 1005);
+        // 1000 ages out
+        assert_eq!(filter.push(20), 1005, "dropout ignored");
+    }
+
+    #[test]
+    fn test_position() {
+        assert_eq!(Calibration::default().position(1000), None);
+
+        // Sensor above the door: the open door sits closer to it.
+        let calibration = Calibration {
+            open_mm: 500,
+            closed_mm: 2500,
+        };
+        assert_eq!(calibration.position(2500), Some(0));
+        assert_eq!(calibration.position(1500), Some(50));
+        assert_eq!(calibration.position(500), Some(100));
+        assert_eq!(calibration.position(3000), Some(0), "clamped");
+        assert_eq!(calibration.position(100), Some(100), "clamped");
+    }
+
+    #[test]
+    fn test_presence() {
+        let mut detector = PresenceDetector::new(1500);
+        assert_eq!(detector.update(900), Some(true), "first reading reported");
+        assert_eq!(detector.update(900), None);
+
+        // A brief gap (someone walking round the car) isn't a departure.
+        for _ in 0..PRESENCE_DEBOUNCE - 1 {
+            assert_eq!(detector.update(2800), None);
+        }
+        assert_eq!(detector.update(900), None);
+
+        for _ in 0..PRESENCE_DEBOUNCE - 1 {
+            assert_eq!(detector.update(2800), None);
+        }
+        assert_eq!(detector.update(2800), Some(false));
+    }
+
+    #[test]
+    fn test_position_changed() {
+        assert!(position_changed(None, 40, 2));
+        assert!(!position_changed(Some(40), 41, 2));
+        assert!(position_changed(Some(40), 42, 2));
+        assert!(!position_changed(Some(40), 49, 10));
+        assert!(position_changed(Some(1), 0, 2));
+        assert!(position_changed(Some(99), 100, 2));
+        assert!(!position_changed(Some(100), 100, 2));
+        assert!(
+            !position_changed(Some(40), 40, 0),
+            "unchanged never reported"
+        );
+    }
+}
@@ -2,11 +2,166 @@ use defmt::{error, info};
 
 use embassy_futures::select;
 use embassy_sync::blocking_mutex::raw::RawMutex;
-use embassy_sync::{channel::Receiver, pubsub::ImmediatePublisher};
+use embassy_time::{Duration, Instant, Timer};
 use embedded_hal::digital::{Error, ErrorType, InputPin, OutputPin, PinState, StatefulOutputPin};
 use embedded_hal_async::digital::Wait;
+use serde::{Deserialize, Serialize};
 
-use crate::state::{AnyState, DoorState, LockState};
+use crate::command::{COMMAND_QUEUE_LEN, CommandQueue, CommandSource, LockAction};
+use crate::state::{AnyState, DoorState, LockState, StatePublisher};
+
+/// How to handle a lock command that arrives while the door is not closed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize, defmt::Format)]
+#[serde(rename_all = "snake_case")]
+pub enum LockWhileOpen {
+    /// Lock straight away, as if the door were closed.
+    #[default]
+    Immediate,
+    /// Accept the command but only lock once the door closes.
+    WhenClosed,
+    /// Refuse the command and report the lock as jammed until the door closes.
+    Reject,
+}
+
+impl From<u8> for LockWhileOpen {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => LockWhileOpen::WhenClosed,
+            2 => LockWhileOpen::Reject,
+            _ => LockWhileOpen::Immediate,
+        }
+    }
+}
+
+impl From<LockWhileOpen> for u8 {
+    fn from(value: LockWhileOpen) -> Self {
+        match value {
+            LockWhileOpen::Immediate => 0,
+            LockWhileOpen::WhenClosed => 1,
+            LockWhileOpen::Reject => 2,
+        }
+    }
+}
+
+impl TryFrom<&str> for LockWhileOpen {
+    type Error = &'static str;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "immediate" => Ok(LockWhileOpen::Immediate),
+            "when_closed" => Ok(LockWhileOpen::WhenClosed),
+            "reject" => Ok(LockWhileOpen::Reject),
+            _ => Err("unknown lock while open policy"),
+        }
+    }
+}
+
+/// Which level of the lock pin unlocks the door.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize, defmt::Format)]
+#[serde(rename_all = "snake_case")]
+pub enum LockPolarity {
+    /// Driving the pin high unlocks the door.
+    #[default]
+    ActiveHigh,
+    /// Driving the pin low unlocks the door, as with many relay boards.
+    ActiveLow,
+}
+
+impl LockPolarity {
+    /// The pin level that leaves the door locked.
+    pub fn locked_level(&self) -> PinState {
+        match self {
+            LockPolarity::ActiveHigh => PinState::Low,
+            LockPolarity::ActiveLow => PinState::High,
+        }
+    }
+}
+
+impl From<u8> for LockPolarity {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => LockPolarity::ActiveLow,
+            _ => LockPolarity::ActiveHigh,
+        }
+    }
+}
+
+impl From<LockPolarity> for u8 {
+    fn from(value: LockPolarity) -> Self {
+        match value {
+            LockPolarity::ActiveHigh => 0,
+            LockPolarity::ActiveLow => 1,
+        }
+    }
+}
+
+impl TryFrom<&str> for LockPolarity {
+    type Error = &'static str;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "active_high" => Ok(LockPolarity::ActiveHigh),
+            "active_low" => Ok(LockPolarity::ActiveLow),
+            _ => Err("unknown lock polarity"),
+        }
+    }
+}
+
+/// What to do with the lock when mains power is lost, while there is still enough stored charge to
+/// drive it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize, defmt::Format)]
+#[serde(rename_all = "snake_case")]
+pub enum PowerFailLock {
+    /// Leave the lock as it is. The strike itself decides what happens once the supply collapses.
+    #[default]
+    Leave,
+    Lock,
+    Unlock,
+}
+
+impl PowerFailLock {
+    /// The command to send when power is lost, if any.
+    pub fn action(&self) -> Option<LockAction> {
+        match self {
+            PowerFailLock::Leave => None,
+            PowerFailLock::Lock => Some(LockAction::Lock),
+            PowerFailLock::Unlock => Some(LockAction::Unlock),
+        }
+    }
+}
+
+impl From<u8> for PowerFailLock {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => PowerFailLock::Lock,
+            2 => PowerFailLock::Unlock,
+            _ => PowerFailLock::Leave,
+        }
+    }
+}
+
+impl From<PowerFailLock> for u8 {
+    fn from(value: PowerFailLock) -> Self {
+        match value {
+            PowerFailLock::Leave => 0,
+            PowerFailLock::Lock => 1,
+            PowerFailLock::Unlock => 2,
+        }
+    }
+}
+
+impl TryFrom<&str> for PowerFailLock {
+    type Error = &'static str;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "leave" => Ok(PowerFailLock::Leave),
+            "lock" => Ok(PowerFailLock::Lock),
+            "unlock" => Ok(PowerFailLock::Unlock),
+            _ => Err("unknown power fail lock state"),
+        }
+    }
+}
 
 pub struct Door<'a, L, R, M>
 where
@@ -14,11 +169,22 @@ where
     R: InputPin + Wait,
     M: RawMutex,
 {
-    cmd_channel: Receiver<'a, M, LockState, 2>,
-    state_channel: ImmediatePublisher<'a, M, AnyState, 2, 6, 0>,
+    commands: &'a CommandQueue<M, COMMAND_QUEUE_LEN>,
+    state_channel: StatePublisher<'a, M>,
     lock_pin: L,
     reed_pin: R,
-    last_reed_state: PinState,
+    open_reed_pin: Option<R>,
+    last_door_state: DoorState,
+    lock_while_open: LockWhileOpen,
+    // A lock command is waiting for the door to close.
+    lock_pending: bool,
+    // The lock has been reported as jammed after refusing a lock command.
+    jammed: bool,
+    lock_polarity: LockPolarity,
+    startup_grace: Duration,
+    buzz_in: Duration,
+    // When to lock again after an open command.
+    relock_at: Option<Instant>,
 }
 
 impl<'a, L, R, M> Door<'a, L, R, M>
@@ -30,21 +196,62 @@ where
     pub fn new(
         lock_pin: L,
         reed_pin: R,
-        cmd_channel: Receiver<'a, M, LockState, 2>,
-        state_channel: ImmediatePublisher<'a, M, AnyState, 2, 6, 0>,
+        commands: &'a CommandQueue<M, COMMAND_QUEUE_LEN>,
+        state_channel: StatePublisher<'a, M>,
     ) -> Self {
         Self {
             lock_pin,
             reed_pin,
-            cmd_channel,
+            open_reed_pin: None,
+            commands,
             state_channel,
-            last_reed_state: PinState::Low,
+            last_door_state: DoorState::Closed,
+            lock_while_open: LockWhileOpen::default(),
+            lock_pending: false,
+            jammed: false,
+            lock_polarity: LockPolarity::default(),
+            startup_grace: Duration::from_ticks(0),
+            buzz_in: Duration::from_secs(5),
+            relock_at: None,
         }
     }
 
+    pub fn with_lock_polarity(mut self, polarity: LockPolarity) -> Self {
+        self.lock_polarity = polarity;
+        self
+    }
+
+    /// How long after starting to hold off publishing state, so reed transitions while the pins
+    /// settle after power on aren't reported.
+    pub fn with_startup_grace(mut self, startup_grace: Duration) -> Self {
+        self.startup_grace = startup_grace;
+        self
+    }
+
+    /// How long an open command leaves the door unlocked for.
+    pub fn with_buzz_in(mut self, buzz_in: Duration) -> Self {
+        self.buzz_in = buzz_in;
+        self
+    }
+
+    pub fn with_lock_while_open(mut self, policy: LockWhileOpen) -> Self {
+        self.lock_while_open = policy;
+        self
+    }
+
+    /// Add a second reed at the fully open position so a partly open door can be reported as
+    /// ajar.
+    pub fn with_open_reed(mut self, open_reed_pin: R) -> Self {
+        self.open_reed_pin = Some(open_reed_pin);
+        self
+    }
+
     pub async fn run(&mut self) {
-        if let Ok(true) = self.reed_pin.is_high() {
-            self.last_reed_state = PinState::High;
+        self.settle().await;
+
+        match self.read_door_state() {
+            Ok(door_state) => self.last_door_state = door_state,
+            Err(e) => error!("error reading reed state: {}", e.kind()),
         }
 
         if let Err(e) = self.lock().await {
@@ -53,71 +260,220 @@ where
 
         // publish initial door states to the state channel
         self.state_channel
-            .publish_immediate(AnyState::DoorState(self.door_state()));
+            .publish(AnyState::DoorState(self.door_state()));
 
         loop {
-            let work = select::select(
-                self.cmd_channel.receive(),
+            let open_reed_pin = &mut self.open_reed_pin;
+            let open_reed_edge = async move {
+                match open_reed_pin {
+                    Some(pin) => pin.wait_for_any_edge().await,
+                    None => core::future::pending().await,
+                }
+            };
+
+            let relock_at = self.relock_at;
+            let relock = async move {
+                match relock_at {
+                    Some(at) => Timer::at(at).await,
+                    None => core::future::pending().await,
+                }
+            };
+
+            let work = select::select4(
+                self.commands.wait(),
                 self.reed_pin.wait_for_any_edge(),
+                open_reed_edge,
+                relock,
             )
             .await;
 
             match work {
-                select::Either::First(LockState::Locked) => {
-                    info!("received lock command");
-                    if let Err(e) = self.lock().await {
-                        error!("error locking door: {}", e.kind());
-                    }
-                }
-                select::Either::First(LockState::Unlocked) => {
-                    info!("received unlock command");
-                    if let Err(e) = self.unlock().await {
-                        error!("error unlocking door: {}", e.kind());
+                select::Either4::First(()) => {
+                    while let Some(command) = self.commands.take(Instant::now()) {
+                        self.command(command.action, command.source).await;
                     }
                 }
-                select::Either::Second(Ok(())) => {
-                    // The door is closed when the reed is "ON" and grounding the pin.
-                    match self.reed_pin.is_low() {
-                        Ok(result) => {
-                            if result {
-                                if self.last_reed_state == PinState::High {
-                                    // High to Low transition
-                                    info!("door is closed");
-                                    self.state_channel
-                                        .publish_immediate(AnyState::DoorState(DoorState::Closed));
-                                }
-                                self.last_reed_state = PinState::Low;
-                            } else {
-                                if self.last_reed_state == PinState::Low {
-                                    // Low to High transition
-                                    info!("door is Open");
-                                    self.state_channel
-                                        .publish_immediate(AnyState::DoorState(DoorState::Open));
+                select::Either4::Second(Ok(())) | select::Either4::Third(Ok(())) => {
+                    match self.read_door_state() {
+                        Ok(door_state) => {
+                            if door_state != self.last_door_state {
+                                info!("door is {}", door_state);
+                                self.last_door_state = door_state;
+                                self.state_channel.publish(AnyState::DoorState(door_state));
+                                if door_state == DoorState::Closed {
+                                    self.door_closed().await;
                                 }
-                                self.last_reed_state = PinState::High;
                             }
                         }
                         Err(e) => error!("error reading reed state: {}", e.kind()),
                     };
                 }
-                select::Either::Second(Err(e)) => {
+                select::Either4::Second(Err(e)) | select::Either4::Third(Err(e)) => {
                     error!("error waiting for reed pin: {}", e.kind());
                 }
+                select::Either4::Fourth(()) => {
+                    info!("buzz-in time over, relocking");
+                    self.relock_at = None;
+                    self.relock().await;
+                }
             }
         }
     }
 
-    pub fn door_state(&self) -> DoorState {
-        match self.last_reed_state {
-            PinState::Low => DoorState::Closed,
-            PinState::High => DoorState::Open,
+    // Wait out the startup grace period with the door locked. Reed transitions are counted but not
+    // published; the state once settled is published as the initial state.
+    async fn settle(&mut self) {
+        if self.startup_grace == Duration::from_ticks(0) {
+            return;
+        }
+
+        if let Err(e) = self.lock_pin.set_state(self.lock_polarity.locked_level()) {
+            error!("error locking door: {}", e.kind());
+        }
+
+        let settled_at = Instant::now() + self.startup_grace;
+        let mut transitions = 0u32;
+        loop {
+            let open_reed_pin = &mut self.open_reed_pin;
+            let open_reed_edge = async move {
+                match open_reed_pin {
+                    Some(pin) => pin.wait_for_any_edge().await,
+                    None => core::future::pending().await,
+                }
+            };
+
+            match select::select3(
+                Timer::at(settled_at),
+                self.reed_pin.wait_for_any_edge(),
+                open_reed_edge,
+            )
+            .await
+            {
+                select::Either3::First(()) => break,
+                select::Either3::Second(Ok(())) | select::Either3::Third(Ok(())) => {
+                    transitions += 1;
+                }
+                select::Either3::Second(Err(e)) | select::Either3::Third(Err(e)) => {
+                    error!("error waiting for reed pin: {}", e.kind());
+                    Timer::at(settled_at).await;
+                    break;
+                }
+            }
+        }
+
+        if transitions > 0 {
+            info!("ignored {} reed transitions while settling", transitions);
+        }
+    }
+
+    async fn command(&mut self, action: LockAction, source: CommandSource) {
+        match action {
+            LockAction::Lock => {
+                info!("received lock command from {}", source);
+                self.relock_at = None;
+                self.lock_command().await;
+            }
+            LockAction::Unlock => {
+                info!("received unlock command from {}", source);
+                self.relock_at = None;
+                self.lock_pending = false;
+                if let Err(e) = self.unlock().await {
+                    error!("error unlocking door: {}", e.kind());
+                }
+            }
+            LockAction::Open => {
+                info!("received open command from {}", source);
+                self.lock_pending = false;
+                self.state_channel
+                    .publish(AnyState::LockState(LockState::Unlocking));
+                if let Err(e) = self.unlock().await {
+                    error!("error unlocking door: {}", e.kind());
+                }
+                self.relock_at = Some(Instant::now() + self.buzz_in);
+            }
+        }
+    }
+
+    // Lock again after a buzz-in. If the visitor is still in the doorway, wait for the door to
+    // close rather than report the lock as jammed and leave it unlocked.
+    async fn relock(&mut self) {
+        if self.last_door_state != DoorState::Closed
+            && self.lock_while_open != LockWhileOpen::Immediate
+        {
+            info!("door is open, relocking once it closes");
+            self.lock_pending = true;
+            return;
+        }
+
+        self.state_channel
+            .publish(AnyState::LockState(LockState::Locking));
+        if let Err(e) = self.lock().await {
+            error!("error locking door: {}", e.kind());
         }
     }
 
+    async fn lock_command(&mut self) {
+        if self.last_door_state != DoorState::Closed {
+            match self.lock_while_open {
+                LockWhileOpen::Immediate => {}
+                LockWhileOpen::WhenClosed => {
+                    info!("door is open, locking once it closes");
+                    self.lock_pending = true;
+                    return;
+                }
+                LockWhileOpen::Reject => {
+                    info!("door is open, refusing to lock");
+                    self.jammed = true;
+                    self.state_channel
+                        .publish(AnyState::LockState(LockState::Jammed));
+                    return;
+                }
+            }
+        }
+
+        if let Err(e) = self.lock().await {
+            error!("error locking door: {}", e.kind());
+        }
+    }
+
+    async fn door_closed(&mut self) {
+        if self.lock_pending {
+            info!("door closed, applying pending lock");
+            if let Err(e) = self.lock().await {
+                error!("error locking door: {}", e.kind());
+            }
+        } else if self.jammed {
+            // Clear the jammed report now the door could be locked.
+            self.jammed = false;
+            let lock_state = self.lock_state();
+            self.state_channel.publish(AnyState::LockState(lock_state));
+        }
+    }
+
+    // A reed is "ON" when the door is at its position, grounding the pin.
+    fn read_door_state(&mut self) -> Result<DoorState, <R as ErrorType>::Error> {
+        if self.reed_pin.is_low()? {
+            return Ok(DoorState::Closed);
+        }
+
+        if let Some(pin) = self.open_reed_pin.as_mut()
+            && pin.is_high()?
+        {
+            return Ok(DoorState::Ajar);
+        }
+
+        Ok(DoorState::Open)
+    }
+
+    pub fn door_state(&self) -> DoorState {
+        self.last_door_state
+    }
+
     pub fn lock_state(&mut self) -> LockState {
-        match self.lock_pin.is_set_low() {
-            Ok(true) => LockState::Locked,
-            Ok(false) => LockState::Unlocked,
+        let locked_high = self.lock_polarity.locked_level() == PinState::High;
+        match self.lock_pin.is_set_high() {
+            Ok(high) if high == locked_high => LockState::Locked,
+            Ok(_) => LockState::Unlocked,
             Err(_) => {
                 error!("door: lock pin state not available");
                 LockState::Unlocked
@@ -126,18 +482,492 @@ where
     }
 
     pub async fn lock(&mut self) -> Result<(), <L as ErrorType>::Error> {
-        self.lock_pin.set_low()?;
+        self.lock_pending = false;
+        self.relock_at = None;
+        self.jammed = false;
+        self.lock_pin.set_state(self.lock_polarity.locked_level())?;
         self.state_channel
-            .publish_immediate(AnyState::LockState(LockState::Locked));
+            .publish(AnyState::LockState(LockState::Locked));
 
         Ok(())
     }
 
     pub async fn unlock(&mut self) -> Result<(), <L as ErrorType>::Error> {
-        self.lock_pin.set_high()?;
+        self.jammed = false;
+        self.lock_pin
+            .set_state(!self.lock_polarity.locked_level())?;
         self.state_channel
-            .publish_immediate(AnyState::LockState(LockState::Unlocked));
+            .publish(AnyState::LockState(LockState::Unlocked));
 
         Ok(())
     }
 }
+
+// End to end tests of the door task: commands go in through the command queue as they would from
+// MQTT, the web interface or UDP, the reed is driven like the real pin, and the resulting state
+// changes are read back off the state pubsub the consumers subscribe to, or from what the MQTT
+// client publishes.
+#[cfg(test)]
+mod tests {
+    use core::cell::Cell;
+    use core::convert::Infallible;
+
+    use embassy_futures::{block_on, select, yield_now};
+    use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex};
+    use embassy_sync::pubsub::{PubSubChannel, Subscriber};
+    use embassy_sync::signal::Signal;
+    use embedded_hal::digital::ErrorType;
+
+    use super::*;
+    use crate::command::COMMAND_TTL;
+    use crate::hass::MQTTContext;
+    use crate::sensors::Sensors;
+    use crate::state::{StateFeed, StateUpdate, StateWatch};
+    use crate::test_support::{Broker, advance};
+
+    struct MockLock<'a> {
+        high: &'a Cell<bool>,
+    }
+
+    impl ErrorType for MockLock<'_> {
+        type Error = Infallible;
+    }
+
+    impl OutputPin for MockLock<'_> {
+        fn set_low(&mut self) -> Result<(), Self::Error> {
+            self.high.set(false);
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Self::Error> {
+            self.high.set(true);
+            Ok(())
+        }
+    }
+
+    impl StatefulOutputPin for MockLock<'_> {
+        fn is_set_high(&mut self) -> Result<bool, Self::Error> {
+            Ok(self.high.get())
+        }
+
+        fn is_set_low(&mut self) -> Result<bool, Self::Error> {
+            Ok(!self.high.get())
+        }
+    }
+
+    struct MockReed<'a> {
+        grounded: &'a Cell<bool>,
+        edge: &'a Signal<NoopRawMutex, ()>,
+    }
+
+    impl MockReed<'_> {
+        async fn wait_until(&mut self, high: bool) -> Result<(), Infallible> {
+            while self.grounded.get() == high {
+                self.edge.wait().await;
+            }
+            Ok(())
+        }
+    }
+
+    impl ErrorType for MockReed<'_> {
+        type Error = Infallible;
+    }
+
+    impl InputPin for MockReed<'_> {
+        fn is_high(&mut self) -> Result<bool, Self::Error> {
+            Ok(!self.grounded.get())
+        }
+
+        fn is_low(&mut self) -> Result<bool, Self::Error> {
+            Ok(self.grounded.get())
+        }
+    }
+
+    impl Wait for MockReed<'_> {
+        async fn wait_for_high(&mut self) -> Result<(), Self::Error> {
+            self.wait_until(true).await
+        }
+
+        async fn wait_for_low(&mut self) -> Result<(), Self::Error> {
+            self.wait_until(false).await
+        }
+
+        async fn wait_for_rising_edge(&mut self) -> Result<(), Self::Error> {
+            self.wait_for_low().await?;
+            self.wait_for_high().await
+        }
+
+        async fn wait_for_falling_edge(&mut self) -> Result<(), Self::Error> {
+            self.wait_for_high().await?;
+            self.wait_for_low().await
+        }
+
+        async fn wait_for_any_edge(&mut self) -> Result<(), Self::Error> {
+            self.edge.wait().await;
+            Ok(())
+        }
+    }
+
+    struct Harness {
+        lock_high: Cell<bool>,
+        reed_grounded: Cell<bool>,
+        reed_edge: Signal<NoopRawMutex, ()>,
+        open_reed_grounded: Cell<bool>,
+        open_reed_edge: Signal<NoopRawMutex, ()>,
+        commands: CommandQueue<CriticalSectionRawMutex, COMMAND_QUEUE_LEN>,
+        updates: PubSubChannel<CriticalSectionRawMutex, StateUpdate, 2, 6, 0>,
+        snapshot: StateWatch<CriticalSectionRawMutex>,
+    }
+
+    impl Harness {
+        fn new() -> Self {
+            Self {
+                lock_high: Cell::new(false),
+                reed_grounded: Cell::new(true),
+                reed_edge: Signal::new(),
+                open_reed_grounded: Cell::new(false),
+                open_reed_edge: Signal::new(),
+                commands: CommandQueue::new(),
+                updates: PubSubChannel::new(),
+                snapshot: StateWatch::new(),
+            }
+        }
+
+        fn door(
+            &self,
+            policy: LockWhileOpen,
+        ) -> Door<'_, MockLock<'_>, MockReed<'_>, CriticalSectionRawMutex> {
+            Door::new(
+                MockLock {
+                    high: &self.lock_high,
+                },
+                MockReed {
+                    grounded: &self.reed_grounded,
+                    edge: &self.reed_edge,
+                },
+                &self.commands,
+                StatePublisher::new(self.updates.immediate_publisher(), self.snapshot.sender()),
+            )
+            .with_lock_while_open(policy)
+        }
+
+        fn open_reed(&self) -> MockReed<'_> {
+            MockReed {
+                grounded: &self.open_reed_grounded,
+                edge: &self.open_reed_edge,
+            }
+        }
+
+        fn send(&self, action: LockAction, source: CommandSource) {
+            self.commands.send(action, source, Instant::now());
+        }
+
+        fn set_door_closed(&self, closed: bool) {
+            self.reed_grounded.set(closed);
+            self.reed_edge.signal(());
+        }
+
+        fn set_door_fully_open(&self, fully_open: bool) {
+            self.open_reed_grounded.set(fully_open);
+            self.open_reed_edge.signal(());
+        }
+
+        fn locked(&self) -> bool {
+            !self.lock_high.get()
+        }
+    }
+
+    type StateSubscriber<'a> = Subscriber<'a, CriticalSectionRawMutex, StateUpdate, 2, 6, 0>;
+
+    async fn expect(sub: &mut StateSubscriber<'_>, state: AnyState) {
+        assert_eq!(sub.next_message_pure().await.state, state);
+    }
+
+    // Run the door task alongside a test scenario until the scenario completes.
+    fn run_scenario<F: Future<Output = ()>>(
+        door: &mut Door<'_, MockLock<'_>, MockReed<'_>, CriticalSectionRawMutex>,
+        scenario: F,
+    ) {
+        block_on(select::select(door.run(), scenario));
+    }
+
+    #[test]
+    fn test_unlock_open_relock() {
+        let harness = Harness::new();
+        let mut sub = harness.updates.subscriber().unwrap();
+        let mut door = harness.door(LockWhileOpen::Immediate);
+
+        run_scenario(&mut door, async {
+            expect(&mut sub, AnyState::LockState(LockState::Locked)).await;
+            expect(&mut sub, AnyState::DoorState(DoorState::Closed)).await;
+
+            harness.send(LockAction::Unlock, CommandSource::Mqtt);
+            expect(&mut sub, AnyState::LockState(LockState::Unlocked)).await;
+            assert!(!harness.locked());
+
+            harness.set_door_closed(false);
+            expect(&mut sub, AnyState::DoorState(DoorState::Open)).await;
+
+            harness.send(LockAction::Lock, CommandSource::Mqtt);
+            expect(&mut sub, AnyState::LockState(LockState::Locked)).await;
+            assert!(harness.locked());
+
+            harness.set_door_closed(true);
+            expect(&mut sub, AnyState::DoorState(DoorState::Closed)).await;
+        });
+
+        let snapshot = harness.snapshot.try_get().unwrap();
+        assert_eq!(snapshot.seq, 6);
+        assert_eq!(snapshot.door_state, Some(DoorState::Closed));
+        assert_eq!(snapshot.lock_state, Some(LockState::Locked));
+    }
+
+    #[test]
+    fn test_ajar() {
+        let harness = Harness::new();
+        let mut sub = harness.updates.subscriber().unwrap();
+        let mut door = harness
+            .door(LockWhileOpen::Immediate)
+            .with_open_reed(harness.open_reed());
+
+        run_scenario(&mut door, async {
+            expect(&mut sub, AnyState::LockState(LockState::Locked)).await;
+            expect(&mut sub, AnyState::DoorState(DoorState::Closed)).await;
+
+            harness.set_door_closed(false);
+            expect(&mut sub, AnyState::DoorState(DoorState::Ajar)).await;
+            harness.set_door_fully_open(true);
+            expect(&mut sub, AnyState::DoorState(DoorState::Open)).await;
+
+            harness.set_door_fully_open(false);
+            expect(&mut sub, AnyState::DoorState(DoorState::Ajar)).await;
+            harness.set_door_closed(true);
+            expect(&mut sub, AnyState::DoorState(DoorState::Closed)).await;
+        });
+
+        let snapshot = harness.snapshot.try_get().unwrap();
+        assert_eq!(snapshot.seq, 6);
+        assert_eq!(snapshot.door_state, Some(DoorState::Closed));
+    }
+
+    #[test]
+    fn test_lock_when_closed() {
+        let harness = Harness::new();
+        let mut sub = harness.updates.subscriber().unwrap();
+        let mut door = harness.door(LockWhileOpen::WhenClosed);
+
+        run_scenario(&mut door, async {
+            expect(&mut sub, AnyState::LockState(LockState::Locked)).await;
+            expect(&mut sub, AnyState::DoorState(DoorState::Closed)).await;
+
+            harness.send(LockAction::Unlock, CommandSource::Web);
+            expect(&mut sub, AnyState::LockState(LockState::Unlocked)).await;
+            harness.set_door_closed(false);
+            expect(&mut sub, AnyState::DoorState(DoorState::Open)).await;
+
+            // Held until the door closes.
+            harness.send(LockAction::Lock, CommandSource::Web);
+            harness.set_door_closed(true);
+            expect(&mut sub, AnyState::DoorState(DoorState::Closed)).await;
+            expect(&mut sub, AnyState::LockState(LockState::Locked)).await;
+            assert!(harness.locked());
+        });
+    }
+
+    #[test]
+    fn test_lock_rejected_while_open() {
+        let harness = Harness::new();
+        let mut sub = harness.updates.subscriber().unwrap();
+        let mut door = harness.door(LockWhileOpen::Reject);
+
+        run_scenario(&mut door, async {
+            expect(&mut sub, AnyState::LockState(LockState::Locked)).await;
+            expect(&mut sub, AnyState::DoorState(DoorState::Closed)).await;
+
+            harness.send(LockAction::Unlock, CommandSource::Udp);
+            expect(&mut sub, AnyState::LockState(LockState::Unlocked)).await;
+            harness.set_door_closed(false);
+            expect(&mut sub, AnyState::DoorState(DoorState::Open)).await;
+
+            harness.send(LockAction::Lock, CommandSource::Udp);
+            expect(&mut sub, AnyState::LockState(LockState::Jammed)).await;
+            assert!(!harness.locked());
+
+            // Closing clears the jam, but doesn't lock.
+            harness.set_door_closed(true);
+            expect(&mut sub, AnyState::DoorState(DoorState::Closed)).await;
+            expect(&mut sub, AnyState::LockState(LockState::Unlocked)).await;
+
+            harness.send(LockAction::Lock, CommandSource::Udp);
+            expect(&mut sub, AnyState::LockState(LockState::Locked)).await;
+        });
+    }
+
+    #[test]
+    fn test_active_low() {
+        let harness = Harness::new();
+        let mut sub = harness.updates.subscriber().unwrap();
+        let mut door = harness
+            .door(LockWhileOpen::Immediate)
+            .with_lock_polarity(LockPolarity::ActiveLow);
+
+        run_scenario(&mut door, async {
+            expect(&mut sub, AnyState::LockState(LockState::Locked)).await;
+            expect(&mut sub, AnyState::DoorState(DoorState::Closed)).await;
+            assert!(harness.lock_high.get(), "held high while locked");
+
+            harness.send(LockAction::Unlock, CommandSource::Web);
+            expect(&mut sub, AnyState::LockState(LockState::Unlocked)).await;
+            assert!(!harness.lock_high.get());
+        });
+        assert_eq!(door.lock_state(), LockState::Unlocked);
+    }
+
+    #[test]
+    fn test_startup_grace() {
+        let harness = Harness::new();
+        let mut sub = harness.updates.subscriber().unwrap();
+        let mut door = harness
+            .door(LockWhileOpen::Immediate)
+            .with_startup_grace(Duration::from_secs(1));
+
+        run_scenario(&mut door, async {
+            harness.set_door_closed(false);
+            yield_now().await;
+            harness.set_door_closed(true);
+            yield_now().await;
+            assert!(sub.try_next_message_pure().is_none());
+            assert!(harness.locked());
+
+            // The state once settled is published when the grace period is over.
+            advance(Duration::from_secs(1));
+            expect(&mut sub, AnyState::LockState(LockState::Locked)).await;
+            expect(&mut sub, AnyState::DoorState(DoorState::Closed)).await;
+        });
+    }
+
+    #[test]
+    fn test_open() {
+        let harness = Harness::new();
+        let mut sub = harness.updates.subscriber().unwrap();
+        let mut door = harness.door(LockWhileOpen::Immediate);
+
+        run_scenario(&mut door, async {
+            expect(&mut sub, AnyState::LockState(LockState::Locked)).await;
+            expect(&mut sub, AnyState::DoorState(DoorState::Closed)).await;
+
+            harness.send(LockAction::Open, CommandSource::Mqtt);
+            expect(&mut sub, AnyState::LockState(LockState::Unlocking)).await;
+            expect(&mut sub, AnyState::LockState(LockState::Unlocked)).await;
+            assert!(!harness.locked());
+
+            advance(Duration::from_secs(4));
+            yield_now().await;
+            assert!(!harness.locked(), "still within the buzz-in");
+
+            advance(Duration::from_secs(1));
+            expect(&mut sub, AnyState::LockState(LockState::Locking)).await;
+            expect(&mut sub, AnyState::LockState(LockState::Locked)).await;
+            assert!(harness.locked());
+
+            // A lock command during the buzz-in locks straight away and cancels the relock.
+            harness.send(LockAction::Open, CommandSource::Mqtt);
+            expect(&mut sub, AnyState::LockState(LockState::Unlocking)).await;
+            expect(&mut sub, AnyState::LockState(LockState::Unlocked)).await;
+            harness.send(LockAction::Lock, CommandSource::Mqtt);
+            expect(&mut sub, AnyState::LockState(LockState::Locked)).await;
+
+            advance(Duration::from_secs(5));
+            yield_now().await;
+            assert!(sub.try_next_message_pure().is_none());
+        });
+        assert!(door.relock_at.is_none());
+    }
+
+    #[test]
+    fn test_expired_command_dropped() {
+        let harness = Harness::new();
+        let mut sub = harness.updates.subscriber().unwrap();
+        let mut door = harness.door(LockWhileOpen::Immediate);
+
+        run_scenario(&mut door, async {
+            expect(&mut sub, AnyState::LockState(LockState::Locked)).await;
+            expect(&mut sub, AnyState::DoorState(DoorState::Closed)).await;
+
+            // Sent long enough ago to have expired by the time the door task reads it.
+            harness.commands.send(
+                LockAction::Unlock,
+                CommandSource::Mqtt,
+                Instant::now() - COMMAND_TTL,
+            );
+            harness.send(LockAction::Lock, CommandSource::Web);
+            expect(&mut sub, AnyState::LockState(LockState::Locked)).await;
+            assert!(harness.commands.is_empty());
+        });
+    }
+
+    #[test]
+    fn test_home_assistant_open() {
+        let harness = Harness::new();
+        let sensors = Sensors::new();
+        let broker = Broker::new();
+        let mut context = MQTTContext::new(b"aabbccddeeff", "Front door", "", "");
+        let mut feed = StateFeed::new(harness.updates.subscriber().unwrap(), &harness.snapshot);
+        let mut door = harness
+            .door(LockWhileOpen::Immediate)
+            .with_open_reed(harness.open_reed());
+
+        let scenario = async {
+            // Connecting brings Home Assistant up to date.
+            broker
+                .published_to("homeassistant/device/aabbccddeeff/config")
+                .await;
+            assert_eq!(
+                broker.published_to("doorctl/aabbccddeeff/reed/state").await,
+                b"OFF"
+            );
+            assert_eq!(
+                broker.published_to("doorctl/aabbccddeeff/lock/state").await,
+                b"LOCKED"
+            );
+
+            broker.publish("doorctl/aabbccddeeff/lock/cmd", b"OPEN");
+            assert_eq!(
+                broker.published_to("doorctl/aabbccddeeff/lock/state").await,
+                b"UNLOCKING"
+            );
+            assert_eq!(
+                broker.published_to("doorctl/aabbccddeeff/lock/state").await,
+                b"UNLOCKED"
+            );
+
+            // The visitor leaves the door ajar.
+            harness.set_door_closed(false);
+            assert_eq!(
+                broker.published_to("doorctl/aabbccddeeff/reed/state").await,
+                b"ON"
+            );
+            let attributes = broker.published_to("doorctl/aabbccddeeff/reed/attr").await;
+            let attributes = str::from_utf8(&attributes).unwrap();
+            assert!(attributes.contains(r#""position":50"#), "{}", attributes);
+
+            advance(Duration::from_secs(5));
+            assert_eq!(
+                broker.published_to("doorctl/aabbccddeeff/lock/state").await,
+                b"LOCKING"
+            );
+            assert_eq!(
+                broker.published_to("doorctl/aabbccddeeff/lock/state").await,
+                b"LOCKED"
+            );
+            assert!(harness.locked());
+        };
+
+        let mqtt = context.run(broker.connection(), &harness.commands, &mut feed, &sensors);
+        if let select::Either3::Second(result) =
+            block_on(select::select3(door.run(), mqtt, scenario))
+        {
+            panic!("mqtt session ended: {:?}", result);
+        }
+    }
+}
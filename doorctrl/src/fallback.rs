@@ -0,0 +1,74 @@
+// Trying out new WiFi settings on a device that may only be reachable over WiFi.
+//
+// When a configuration change moves the device to another network the previous configuration is
+// kept (see `ConfigV1::save_fallback`). Until the device has connected with the new settings they
+// are on trial: enough failed connection attempts in a row and the device falls back to the
+// previous configuration rather than dropping off the network for good.
+
+/// Failed connection attempts in a row before new WiFi settings are given up on.
+pub const FALLBACK_AFTER_FAILURES: u8 = 5;
+
+#[derive(Debug, Default)]
+pub struct NetworkTrial {
+    // New settings are waiting to be proven, with a configuration kept to fall back to.
+    pending: bool,
+    failures: u8,
+}
+
+impl NetworkTrial {
+    /// Start a trial if there is a configuration to fall back to.
+    pub fn new(pending: bool) -> Self {
+        Self {
+            pending,
+            failures: 0,
+        }
+    }
+
+    pub fn pending(&self) -> bool {
+        self.pending
+    }
+
+    /// Record a successful connection, returning true if it proves new settings so the fallback
+    /// can be discarded.
+    pub fn connected(&mut self) -> bool {
+        let proven = self.pending;
+        self.pending = false;
+        self.failures = 0;
+        proven
+    }
+
+    /// Record a failed connection, returning true once new settings have failed often enough to
+    /// fall back.
+    pub fn failed(&mut self) -> bool {
+        if !self.pending {
+            return false;
+        }
+
+        self.failures = self.failures.saturating_add(1);
+        self.failures >= FALLBACK_AFTER_FAILURES
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_network_trial() {
+        let mut trial = NetworkTrial::new(true);
+        for _ in 1..FALLBACK_AFTER_FAILURES {
+            assert!(!trial.failed());
+        }
+        assert!(trial.failed(), "falls back after repeated failures");
+
+        let mut trial = NetworkTrial::new(true);
+        assert!(!trial.failed());
+        assert!(trial.connected(), "first connection proves the settings");
+        assert!(!trial.pending());
+        assert!(!trial.connected());
+
+        for _ in 0..FALLBACK_AFTER_FAILURES {
+            assert!(!trial.failed(), "proven settings are never given up on");
+        }
+    }
+}
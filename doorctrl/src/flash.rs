@@ -0,0 +1,80 @@
+// Flash operations that can take long enough to starve other tasks.
+//
+// Erasing a sector blocks for tens of milliseconds, so erasing many in one call would hold up the
+// door task and the watchdog feeding for the whole region. Erasing a sector at a time and yielding
+// in between lets the executor run other tasks as the erase goes.
+use embassy_futures::yield_now;
+use embedded_storage::nor_flash::NorFlash;
+
+/// Erase `from..to` one sector at a time, yielding to other tasks after each sector.
+pub async fn erase<S: NorFlash>(flash: &mut S, from: u32, to: u32) -> Result<(), S::Error> {
+    let sector = S::ERASE_SIZE as u32;
+    let mut offset = from;
+    while offset < to {
+        let end = (offset + sector).min(to);
+        flash.erase(offset, end)?;
+        offset = end;
+        yield_now().await;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+    use embedded_storage::nor_flash::{ErrorType, NorFlashErrorKind, ReadNorFlash};
+    use heapless::Vec;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct MockFlash {
+        erased: Vec<(u32, u32), 8>,
+    }
+
+    impl ErrorType for MockFlash {
+        type Error = NorFlashErrorKind;
+    }
+
+    impl ReadNorFlash for MockFlash {
+        const READ_SIZE: usize = 1;
+
+        fn read(&mut self, _offset: u32, _bytes: &mut [u8]) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            4 * 4096
+        }
+    }
+
+    impl NorFlash for MockFlash {
+        const WRITE_SIZE: usize = 4;
+        const ERASE_SIZE: usize = 4096;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+            if !from.is_multiple_of(4096) || !to.is_multiple_of(4096) {
+                return Err(NorFlashErrorKind::NotAligned);
+            }
+            self.erased.push((from, to)).unwrap();
+            Ok(())
+        }
+
+        fn write(&mut self, _offset: u32, _bytes: &[u8]) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_erase_by_sector() {
+        let mut flash = MockFlash::default();
+        block_on(erase(&mut flash, 4096, 4 * 4096)).unwrap();
+        assert_eq!(flash.erased, [(4096, 8192), (8192, 12288), (12288, 16384)]);
+
+        assert_eq!(
+            block_on(erase(&mut flash, 100, 4096)),
+            Err(NorFlashErrorKind::NotAligned)
+        );
+    }
+}
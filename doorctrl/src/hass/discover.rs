@@ -3,19 +3,35 @@ use serde::Serialize;
 const DEFAULT_DEVICE_NAME: &str = "Door";
 const DEFAULT_LOCK_ID: &str = "door_lock";
 const DEFAULT_SENSOR_ID: &str = "door_sensor";
+const DEFAULT_COVER_ID: &str = "door_position";
+const DEFAULT_RSSI_ID: &str = "wifi_rssi";
 
 const MQTT_PAYLOAD_AVAILABLE: &str = "online";
 const MQTT_PAYLOAD_NOT_AVAILABLE: &str = "offline";
 const MQTT_AVAILABILITY_MODE: &str = "latest";
+const MQTT_AVAILABILITY_TEMPLATE: &str = "{{ value_json.state }}";
 const MQTT_PAYLOAD_LOCK: &str = "LOCK";
 const MQTT_PAYLOAD_UNLOCK: &str = "UNLOCK";
+const MQTT_PAYLOAD_OPEN: &str = "OPEN";
 const MQTT_STATE_LOCKED: &str = "LOCKED";
 const MQTT_STATE_UNLOCKED: &str = "UNLOCKED";
+const MQTT_STATE_LOCKING: &str = "LOCKING";
+const MQTT_STATE_UNLOCKING: &str = "UNLOCKING";
+const MQTT_STATE_JAMMED: &str = "JAMMED";
 const MQTT_STATE_OFF: &str = "OFF";
 const MQTT_STATE_ON: &str = "ON";
 const MQTT_PLATFORM_LOCK: &str = "lock";
 const MQTT_PLATFORM_BINARY_SENSOR: &str = "binary_sensor";
 const MQTT_DEVICE_CLASS_BINARY_SENSOR: &str = "door";
+const MQTT_PLATFORM_COVER: &str = "cover";
+const MQTT_DEVICE_CLASS_COVER: &str = "garage";
+const MQTT_DEVICE_CLASS_VEHICLE: &str = "occupancy";
+const MQTT_PLATFORM_SENSOR: &str = "sensor";
+const MQTT_DEVICE_CLASS_SIGNAL_STRENGTH: &str = "signal_strength";
+const MQTT_UNIT_DBM: &str = "dBm";
+const MQTT_STATE_CLASS_MEASUREMENT: &str = "measurement";
+// Home Assistant lists diagnostic entities apart from the device's controls and sensors.
+const MQTT_ENTITY_CATEGORY_DIAGNOSTIC: &str = "diagnostic";
 
 const MQTT_ORIGIN_NAME: &str = "doorctl";
 const MQTT_ORIGIN_SW_VERSION: &str = "0.0.1";
@@ -58,14 +74,19 @@ struct ComponentLock<'a> {
     unique_id: &'a str,
     object_id: &'a str,
     platform: &'static str,
-    name: &'static str,
+    name: &'a str,
     enabled_by_default: bool,
     state_topic: &'a str,
     command_topic: &'a str,
+    json_attributes_topic: &'a str,
     payload_lock: &'static str,
     payload_unlock: &'static str,
+    payload_open: &'static str,
     state_locked: &'static str,
     state_unlocked: &'static str,
+    state_locking: &'static str,
+    state_unlocking: &'static str,
+    state_jammed: &'static str,
     optimistic: bool,
     retain: bool,
 }
@@ -80,10 +101,15 @@ impl<'a> Default for ComponentLock<'a> {
             enabled_by_default: true,
             state_topic: "",
             command_topic: "",
+            json_attributes_topic: "",
             payload_lock: MQTT_PAYLOAD_LOCK,
             payload_unlock: MQTT_PAYLOAD_UNLOCK,
+            payload_open: MQTT_PAYLOAD_OPEN,
             state_locked: MQTT_STATE_LOCKED,
             state_unlocked: MQTT_STATE_UNLOCKED,
+            state_locking: MQTT_STATE_LOCKING,
+            state_unlocking: MQTT_STATE_UNLOCKING,
+            state_jammed: MQTT_STATE_JAMMED,
             optimistic: false,
             retain: false,
         }
@@ -95,10 +121,12 @@ struct ComponentBinarySensor<'a> {
     unique_id: &'a str,
     object_id: &'a str,
     device_class: &'static str,
-    name: &'static str,
+    name: &'a str,
     platform: &'static str,
     enabled_by_default: bool,
     state_topic: &'a str,
+    #[serde(skip_serializing_if = "str::is_empty")]
+    json_attributes_topic: &'a str,
     payload_on: &'static str,
     payload_off: &'static str,
     optimistic: bool,
@@ -115,6 +143,7 @@ impl<'a> Default for ComponentBinarySensor<'a> {
             platform: MQTT_PLATFORM_BINARY_SENSOR,
             enabled_by_default: true,
             state_topic: "",
+            json_attributes_topic: "",
             payload_on: MQTT_STATE_ON,
             payload_off: MQTT_STATE_OFF,
             optimistic: false,
@@ -123,10 +152,83 @@ impl<'a> Default for ComponentBinarySensor<'a> {
     }
 }
 
+// Read only (no command topic), reporting the position measured by a distance sensor.
+#[derive(Serialize)]
+struct ComponentCover<'a> {
+    unique_id: &'a str,
+    object_id: &'a str,
+    device_class: &'static str,
+    name: &'static str,
+    platform: &'static str,
+    enabled_by_default: bool,
+    position_topic: &'a str,
+    position_open: u8,
+    position_closed: u8,
+    optimistic: bool,
+    retain: bool,
+}
+
+impl<'a> Default for ComponentCover<'a> {
+    fn default() -> Self {
+        Self {
+            unique_id: DEFAULT_COVER_ID,
+            object_id: DEFAULT_COVER_ID,
+            device_class: MQTT_DEVICE_CLASS_COVER,
+            name: "Position",
+            platform: MQTT_PLATFORM_COVER,
+            enabled_by_default: true,
+            position_topic: "",
+            position_open: 100,
+            position_closed: 0,
+            optimistic: false,
+            retain: false,
+        }
+    }
+}
+
+// A numeric sensor describing the device itself rather than the door.
+#[derive(Serialize)]
+struct ComponentSensor<'a> {
+    unique_id: &'a str,
+    object_id: &'a str,
+    device_class: &'static str,
+    name: &'static str,
+    platform: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    entity_category: Option<&'static str>,
+    enabled_by_default: bool,
+    state_topic: &'a str,
+    unit_of_measurement: &'static str,
+    state_class: &'static str,
+}
+
+impl<'a> Default for ComponentSensor<'a> {
+    fn default() -> Self {
+        Self {
+            unique_id: DEFAULT_RSSI_ID,
+            object_id: DEFAULT_RSSI_ID,
+            device_class: MQTT_DEVICE_CLASS_SIGNAL_STRENGTH,
+            name: "WiFi signal",
+            platform: MQTT_PLATFORM_SENSOR,
+            entity_category: Some(MQTT_ENTITY_CATEGORY_DIAGNOSTIC),
+            enabled_by_default: true,
+            state_topic: "",
+            unit_of_measurement: MQTT_UNIT_DBM,
+            state_class: MQTT_STATE_CLASS_MEASUREMENT,
+        }
+    }
+}
+
 #[derive(Serialize, Default)]
 struct DiscoveryComponents<'a> {
     lock: ComponentLock<'a>,
     reed: ComponentBinarySensor<'a>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cover: Option<ComponentCover<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vehicle: Option<ComponentBinarySensor<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rssi: Option<ComponentSensor<'a>>,
 }
 
 #[derive(Serialize, Default)]
@@ -136,6 +238,7 @@ pub(crate) struct Discovery<'a> {
     components: DiscoveryComponents<'a>,
     availability_topic: &'a str,
     availability_mode: &'static str,
+    availability_template: &'static str,
     qos: u8,
 }
 
@@ -155,6 +258,7 @@ impl<'a> Discovery<'a> {
         disc.device.name = device_name;
         disc.availability_topic = avail_topic;
         disc.availability_mode = MQTT_AVAILABILITY_MODE;
+        disc.availability_template = MQTT_AVAILABILITY_TEMPLATE;
         disc.components.lock.unique_id = lock_id;
         disc.components.lock.object_id = lock_id;
         disc.components.lock.state_topic = lock_state_topic;
@@ -164,4 +268,100 @@ impl<'a> Discovery<'a> {
         disc.components.reed.state_topic = reed_state_topic;
         disc
     }
+
+    pub(crate) fn with_attributes_topics(
+        mut self,
+        lock_attributes_topic: &'a str,
+        reed_attributes_topic: &'a str,
+    ) -> Self {
+        self.components.lock.json_attributes_topic = lock_attributes_topic;
+        self.components.reed.json_attributes_topic = reed_attributes_topic;
+        self
+    }
+
+    /// Override the default names of the lock and door entities. An empty name keeps the default.
+    pub(crate) fn with_names(mut self, lock_name: &'a str, door_name: &'a str) -> Self {
+        if !lock_name.is_empty() {
+            self.components.lock.name = lock_name;
+        }
+        if !door_name.is_empty() {
+            self.components.reed.name = door_name;
+        }
+        self
+    }
+
+    pub(crate) fn with_cover(mut self, cover_id: &'a str, position_topic: &'a str) -> Self {
+        self.components.cover = Some(ComponentCover {
+            unique_id: cover_id,
+            object_id: cover_id,
+            position_topic,
+            ..Default::default()
+        });
+        self
+    }
+
+    pub(crate) fn with_vehicle(mut self, vehicle_id: &'a str, state_topic: &'a str) -> Self {
+        self.components.vehicle = Some(ComponentBinarySensor {
+            unique_id: vehicle_id,
+            object_id: vehicle_id,
+            device_class: MQTT_DEVICE_CLASS_VEHICLE,
+            name: "Vehicle",
+            state_topic,
+            ..Default::default()
+        });
+        self
+    }
+
+    /// Advertise the WiFi signal strength as a diagnostic sensor.
+    pub(crate) fn with_rssi(mut self, rssi_id: &'a str, state_topic: &'a str) -> Self {
+        self.components.rssi = Some(ComponentSensor {
+            unique_id: rssi_id,
+            object_id: rssi_id,
+            state_topic,
+            ..Default::default()
+        });
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json_core::to_slice;
+
+    use super::*;
+
+    #[test]
+    fn test_with_names() {
+        let discovery = Discovery::new("Door", "id", "lock", "sensor", "", "", "", "")
+            .with_names("Front Door Deadbolt", "");
+
+        let mut json = [0u8; 2048];
+        let len = to_slice(&discovery, &mut json[..]).unwrap();
+        let json = str::from_utf8(&json[..len]).unwrap();
+        assert!(json.contains("\"name\":\"Front Door Deadbolt\""));
+        assert!(
+            json.contains("\"name\":\"Door\",\"platform\""),
+            "door keeps default"
+        );
+    }
+
+    #[test]
+    fn test_with_rssi() {
+        let discovery = Discovery::new("Door", "id", "lock", "sensor", "", "", "", "");
+        let mut json = [0u8; 2048];
+        let len = to_slice(&discovery, &mut json[..]).unwrap();
+        let json = str::from_utf8(&json[..len]).unwrap();
+        assert!(
+            !json.contains("entity_category"),
+            "door entities are primary"
+        );
+
+        let discovery = discovery.with_rssi("rssi", "doorctl/id/rssi/state");
+        let mut json = [0u8; 2048];
+        let len = to_slice(&discovery, &mut json[..]).unwrap();
+        let json = str::from_utf8(&json[..len]).unwrap();
+        assert!(json.contains(
+            "\"rssi\":{\"unique_id\":\"rssi\",\"object_id\":\"rssi\",\"device_class\":\"signal_strength\",\"name\":\"WiFi signal\",\"platform\":\"sensor\",\"entity_category\":\"diagnostic\""
+        ));
+    }
 }
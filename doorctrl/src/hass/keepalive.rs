@@ -0,0 +1,109 @@
+// How often to ping the broker. A dead connection is only noticed when a ping goes unanswered, so
+// on a weak or flaky link the interval shortens to notice sooner, and on a good link it stays long
+// to save airtime.
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::Duration;
+
+/// Ping interval on a good link, and the longest used.
+pub const KEEPALIVE_MAX_SECS: u64 = 60;
+/// Shortest ping interval used.
+pub const KEEPALIVE_MIN_SECS: u64 = 10;
+
+// Signal strength (dBm) below which the link is fair or poor.
+const RSSI_FAIR: i8 = -67;
+const RSSI_POOR: i8 = -75;
+
+// Each recent reconnect halves the interval, up to this many.
+const MAX_RECONNECTS: u8 = 2;
+// Pings answered in a row before a reconnect is forgotten.
+const STABLE_PINGS: u8 = 10;
+
+/// Latest WiFi signal strength, shared between the WiFi task sampling it and the MQTT task.
+pub struct LinkQuality {
+    rssi: Mutex<CriticalSectionRawMutex, Cell<Option<i8>>>,
+}
+
+impl LinkQuality {
+    pub const fn new() -> Self {
+        Self {
+            rssi: Mutex::new(Cell::new(None)),
+        }
+    }
+
+    pub fn set_rssi(&self, rssi: Option<i8>) {
+        self.rssi.lock(|cell| cell.set(rssi));
+    }
+
+    pub fn rssi(&self) -> Option<i8> {
+        self.rssi.lock(|cell| cell.get())
+    }
+}
+
+impl Default for LinkQuality {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Default)]
+pub struct KeepAlive {
+    reconnects: u8,
+    pings: u8,
+}
+
+impl KeepAlive {
+    /// Record that the connection to the broker was lost and made again.
+    pub fn reconnected(&mut self) {
+        self.reconnects = (self.reconnects + 1).min(MAX_RECONNECTS);
+        self.pings = 0;
+    }
+
+    /// Record an answered ping.
+    pub fn pinged(&mut self) {
+        self.pings += 1;
+        if self.pings >= STABLE_PINGS {
+            self.pings = 0;
+            self.reconnects = self.reconnects.saturating_sub(1);
+        }
+    }
+
+    /// The ping interval for a link with signal strength `rssi`, if known.
+    pub fn interval(&self, rssi: Option<i8>) -> Duration {
+        let secs = match rssi {
+            Some(rssi) if rssi < RSSI_POOR => KEEPALIVE_MAX_SECS / 4,
+            Some(rssi) if rssi < RSSI_FAIR => KEEPALIVE_MAX_SECS / 2,
+            _ => KEEPALIVE_MAX_SECS,
+        };
+
+        Duration::from_secs((secs >> self.reconnects).max(KEEPALIVE_MIN_SECS))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interval() {
+        let mut keepalive = KeepAlive::default();
+        assert_eq!(keepalive.interval(None), Duration::from_secs(60));
+        assert_eq!(keepalive.interval(Some(-60)), Duration::from_secs(60));
+        assert_eq!(keepalive.interval(Some(-70)), Duration::from_secs(30));
+        assert_eq!(keepalive.interval(Some(-80)), Duration::from_secs(15));
+
+        keepalive.reconnected();
+        assert_eq!(keepalive.interval(Some(-60)), Duration::from_secs(30));
+        keepalive.reconnected();
+        keepalive.reconnected();
+        assert_eq!(keepalive.interval(Some(-60)), Duration::from_secs(15));
+        assert_eq!(keepalive.interval(Some(-80)), Duration::from_secs(10));
+
+        // A stable connection earns the long interval back.
+        for _ in 0..2 * STABLE_PINGS {
+            keepalive.pinged();
+        }
+        assert_eq!(keepalive.interval(Some(-60)), Duration::from_secs(60));
+    }
+}
@@ -3,16 +3,17 @@
 #![allow(dead_code)]
 
 pub mod discover;
+pub mod keepalive;
+pub mod names;
 mod topic;
 
 use core::str;
-use defmt::{error, info};
+use defmt::{error, info, warn};
 
 use embassy_futures::select;
-use embassy_sync::{
-    blocking_mutex::raw::CriticalSectionRawMutex, channel::Sender, pubsub::Subscriber,
-};
-use embassy_time::{Duration, Timer};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
 use embedded_io_async::{Read, Write};
 
 use rust_mqtt::{
@@ -20,29 +21,49 @@ use rust_mqtt::{
     packet::v5::{publish_packet::QualityOfService, reason_codes::ReasonCode},
     utils::rng_generator::CountingRng,
 };
+use serde::Serialize;
 use serde_json_core::to_slice;
 
-use crate::state::{AnyState, DoorState, LockState};
+use crate::command::{COMMAND_QUEUE_LEN, CommandQueue, CommandSource, LockAction};
+use crate::reset::ResetReason;
+use crate::sensors::{SensorReading, Sensors};
+use crate::state::{AnyState, DoorState, LockState, StateEvent, StateFeed, StateSnapshot};
 
 use discover::Discovery;
+use keepalive::{KeepAlive, LinkQuality};
+use names::FriendlyNames;
 use topic::{
-    mk_availability_topic, mk_discovery_topic, mk_lock_cmd_topic, mk_lock_state_topic,
-    mk_sensor_state_topic,
+    cmd_topic_entity, mk_availability_topic, mk_cmd_wildcard_topic, mk_cover_position_topic,
+    mk_discovery_topic, mk_lock_attributes_topic, mk_lock_cmd_legacy_topic, mk_lock_cmd_topic,
+    mk_lock_state_topic, mk_rssi_state_topic, mk_sensor_attributes_topic, mk_sensor_state_topic,
+    mk_vehicle_state_topic,
 };
 
 const MQTT_PAYLOAD_AVAILABLE: &str = "online";
 const MQTT_PAYLOAD_NOT_AVAILABLE: &str = "offline";
 const MQTT_PAYLOAD_LOCK: &str = "LOCK";
 const MQTT_PAYLOAD_UNLOCK: &str = "UNLOCK";
+const MQTT_PAYLOAD_OPEN: &str = "OPEN";
 const MQTT_STATE_LOCKED: &str = "LOCKED";
 const MQTT_STATE_UNLOCKED: &str = "UNLOCKED";
+const MQTT_STATE_LOCKING: &str = "LOCKING";
+const MQTT_STATE_UNLOCKING: &str = "UNLOCKING";
+const MQTT_STATE_JAMMED: &str = "JAMMED";
 const MQTT_STATE_OFF: &str = "OFF";
 const MQTT_STATE_ON: &str = "ON";
 const MQTT_LOCK_ID_SUFFIX: &str = "_lock";
 const MQTT_SENSOR_ID_SUFFIX: &str = "_sensor";
+const MQTT_COVER_ID_SUFFIX: &str = "_cover";
+const MQTT_VEHICLE_ID_SUFFIX: &str = "_vehicle";
+const MQTT_RSSI_ID_SUFFIX: &str = "_rssi";
+const MQTT_ENTITY_LOCK: &str = "lock";
+const MQTT_SOURCE_LOCK: &str = "lock";
+const MQTT_SOURCE_REED: &str = "reed";
 
-const BUFFER_LEN: usize = 1024;
-const MQTT_KEEPALIVE: u64 = 60;
+// The discovery payload is the largest message sent. Leave room in the buffers for its topic and
+// the packet header.
+const DISCOVERY_LEN: usize = 2560;
+const BUFFER_LEN: usize = DISCOVERY_LEN + 512;
 
 pub fn make_buffers() -> [[u8; BUFFER_LEN]; 2] {
     let rx = [0u8; BUFFER_LEN];
@@ -50,6 +71,35 @@ pub fn make_buffers() -> [[u8; BUFFER_LEN]; 2] {
     [rx, tx]
 }
 
+/// Published alongside each state change so consumers can order transitions and detect any they
+/// missed while disconnected.
+#[derive(Serialize)]
+struct StateAttributes<'a> {
+    state: &'a str,
+    uptime_ms: u64,
+    seq: u32,
+    source: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    position: Option<u8>,
+}
+
+/// Published, retained, to the availability topic. Home Assistant reads `state` from it through the
+/// discovery `availability_template`.
+#[derive(Serialize)]
+struct Availability {
+    state: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uptime_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reset_reason: Option<ResetReason>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    boot_count: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keepalive_s: Option<u64>,
+    #[serde(skip_serializing_if = "core::ops::Not::not")]
+    power_fail: bool,
+}
+
 pub struct MQTTContext<'a> {
     device_id: &'a [u8; 12],
     device_name: &'a str,
@@ -58,8 +108,27 @@ pub struct MQTTContext<'a> {
     discovery_topic: [u8; topic::MQTT_TOPIC_DISCOVERY_LEN],
     availability_topic: [u8; topic::MQTT_TOPIC_AVAILABILITY_LEN],
     lock_cmd_topic: [u8; topic::MQTT_TOPIC_LOCK_COMMAND_LEN],
+    lock_cmd_legacy_topic: [u8; topic::MQTT_TOPIC_LOCK_COMMAND_LEGACY_LEN],
+    cmd_wildcard_topic: [u8; topic::MQTT_TOPIC_COMMAND_WILDCARD_LEN],
     lock_state_topic: [u8; topic::MQTT_TOPIC_LOCK_STATE_LEN],
     sensor_state_topic: [u8; topic::MQTT_TOPIC_SENSOR_STATE_LEN],
+    lock_attributes_topic: [u8; topic::MQTT_TOPIC_LOCK_ATTRIBUTES_LEN],
+    sensor_attributes_topic: [u8; topic::MQTT_TOPIC_SENSOR_ATTRIBUTES_LEN],
+    cover_position_topic: [u8; topic::MQTT_TOPIC_COVER_POSITION_LEN],
+    vehicle_state_topic: [u8; topic::MQTT_TOPIC_VEHICLE_STATE_LEN],
+    rssi_state_topic: [u8; topic::MQTT_TOPIC_RSSI_STATE_LEN],
+    position_sensor: bool,
+    vehicle_sensor: bool,
+    reset_reason: ResetReason,
+    boot_count: Option<u32>,
+    link: Option<&'a LinkQuality>,
+    names: Option<&'a FriendlyNames>,
+    power_fail: Option<&'a Signal<CriticalSectionRawMutex, ()>>,
+    // Set once power has failed. The signal is cleared by waiting on it, so this is what keeps a
+    // reconnect from reporting the device online again.
+    power_failed: bool,
+    keepalive: KeepAlive,
+    sessions: u32,
 }
 
 impl<'a> MQTTContext<'a> {
@@ -77,83 +146,227 @@ impl<'a> MQTTContext<'a> {
             discovery_topic: mk_discovery_topic(device_id),
             availability_topic: mk_availability_topic(device_id),
             lock_cmd_topic: mk_lock_cmd_topic(device_id),
+            lock_cmd_legacy_topic: mk_lock_cmd_legacy_topic(device_id),
+            cmd_wildcard_topic: mk_cmd_wildcard_topic(device_id),
             lock_state_topic: mk_lock_state_topic(device_id),
             sensor_state_topic: mk_sensor_state_topic(device_id),
+            lock_attributes_topic: mk_lock_attributes_topic(device_id),
+            sensor_attributes_topic: mk_sensor_attributes_topic(device_id),
+            cover_position_topic: mk_cover_position_topic(device_id),
+            vehicle_state_topic: mk_vehicle_state_topic(device_id),
+            rssi_state_topic: mk_rssi_state_topic(device_id),
+            position_sensor: false,
+            vehicle_sensor: false,
+            reset_reason: ResetReason::Unknown,
+            boot_count: None,
+            link: None,
+            names: None,
+            power_fail: None,
+            power_failed: false,
+            keepalive: KeepAlive::default(),
+            sessions: 0,
         }
     }
 
+    /// Advertise a cover reporting the door position from a distance sensor.
+    pub fn with_position_sensor(mut self) -> Self {
+        self.position_sensor = true;
+        self
+    }
+
+    /// Advertise a binary sensor reporting whether a vehicle is parked.
+    pub fn with_vehicle_sensor(mut self) -> Self {
+        self.vehicle_sensor = true;
+        self
+    }
+
+    /// Why the device last restarted, reported with its availability.
+    pub fn with_reset_reason(mut self, reset_reason: ResetReason) -> Self {
+        self.reset_reason = reset_reason;
+        self
+    }
+
+    /// How many times the device has started, reported with its availability.
+    pub fn with_boot_count(mut self, boot_count: u32) -> Self {
+        self.boot_count = Some(boot_count);
+        self
+    }
+
+    /// Shorten the keepalive interval when the WiFi signal is weak, and report the signal strength
+    /// as a diagnostic sensor.
+    pub fn with_link_quality(mut self, link: &'a LinkQuality) -> Self {
+        self.link = Some(link);
+        self
+    }
+
+    /// Name the lock and door entities in discovery, publishing it again whenever the names change.
+    pub fn with_friendly_names(mut self, names: &'a FriendlyNames) -> Self {
+        self.names = Some(names);
+        self
+    }
+
+    /// Report the device offline as soon as `power_fail` is signalled, rather than when the broker
+    /// notices the connection has gone. It stays reported offline, across reconnects, until restart.
+    pub fn with_power_fail(mut self, power_fail: &'a Signal<CriticalSectionRawMutex, ()>) -> Self {
+        self.power_fail = Some(power_fail);
+        self
+    }
+
+    /// The current interval between pings to the broker.
+    pub fn keepalive_interval(&self) -> Duration {
+        self.keepalive
+            .interval(self.link.and_then(|link| link.rssi()))
+    }
+
     pub async fn connect<T: Read + Write>(
         &self,
         client: &mut MqttClient<'a, T, 3, CountingRng>,
     ) -> Result<(), ReasonCode> {
         client.connect_to_broker().await?;
+        self.send_discovery(client).await?;
 
-        let mut lock_id: [u8; 17] = [0u8; 17];
-        lock_id[..12].copy_from_slice(self.device_id);
-        lock_id[12..].copy_from_slice(MQTT_LOCK_ID_SUFFIX.as_bytes());
-
-        let mut sensor_id: [u8; 19] = [0u8; 19];
-        sensor_id[..12].copy_from_slice(self.device_id);
-        sensor_id[12..].copy_from_slice(MQTT_SENSOR_ID_SUFFIX.as_bytes());
-
-        let discovery_payload = Discovery::new(
-            self.device_name,
-            str::from_utf8(self.device_id).unwrap(),
-            str::from_utf8(&lock_id).unwrap(),
-            str::from_utf8(&sensor_id).unwrap(),
-            str::from_utf8(&self.availability_topic).unwrap(),
-            str::from_utf8(&self.lock_state_topic).unwrap(),
-            str::from_utf8(&self.lock_cmd_topic).unwrap(),
-            str::from_utf8(&self.sensor_state_topic).unwrap(),
-        );
+        if self.power_failed {
+            return self.send_power_fail(client).await;
+        }
 
-        let mut discovery_payload_json = [0u8; 1024];
-        let len = to_slice(&discovery_payload, &mut discovery_payload_json[..]).unwrap();
+        let availability = Availability {
+            state: MQTT_PAYLOAD_AVAILABLE,
+            uptime_ms: Some(Instant::now().as_millis()),
+            reset_reason: Some(self.reset_reason),
+            boot_count: self.boot_count,
+            keepalive_s: Some(self.keepalive_interval().as_secs()),
+            power_fail: false,
+        };
+        let mut availability_json = [0u8; 128];
+        let availability_json = to_json(&availability, &mut availability_json, "availability")?;
         if let Err(e) = client
             .send_message(
-                str::from_utf8(&self.discovery_topic).unwrap(),
-                &discovery_payload_json[..len],
+                str::from_utf8(&self.availability_topic).unwrap(),
+                availability_json,
                 QualityOfService::QoS1,
-                false,
+                true,
             )
             .await
         {
-            error!("failed to send discovery payload: {}", e);
+            error!("failed to send availability message: {}", e);
             return Err(e);
         }
-        info!("discovery sent to {}", self.discovery_topic);
-        info!(
-            "{}",
-            str::from_utf8(&discovery_payload_json[..len]).unwrap()
-        );
 
+        Ok(())
+    }
+
+    async fn send_discovery<T: Read + Write>(
+        &self,
+        client: &mut MqttClient<'a, T, 3, CountingRng>,
+    ) -> Result<(), ReasonCode> {
+        let (lock_name, door_name) = self.names.and_then(|names| names.get()).unwrap_or_default();
+
+        let mut discovery_payload_json = [0u8; DISCOVERY_LEN];
+        let discovery_payload_json = self.discovery_json(
+            lock_name.as_str(),
+            door_name.as_str(),
+            &mut discovery_payload_json,
+        )?;
         if let Err(e) = client
             .send_message(
-                str::from_utf8(&self.availability_topic).unwrap(),
-                MQTT_PAYLOAD_AVAILABLE.as_bytes(),
+                str::from_utf8(&self.discovery_topic).unwrap(),
+                discovery_payload_json,
                 QualityOfService::QoS1,
-                true,
+                false,
             )
             .await
         {
-            error!("failed to send availability message: {}", e);
+            error!("failed to send discovery payload: {}", e);
             return Err(e);
         }
+        info!("discovery sent to {}", self.discovery_topic);
+        info!("{}", str::from_utf8(discovery_payload_json).unwrap());
 
         Ok(())
     }
 
+    /// Serialize the discovery payload for every entity the device has into `buf`.
+    fn discovery_json<'b>(
+        &self,
+        lock_name: &str,
+        door_name: &str,
+        buf: &'b mut [u8],
+    ) -> Result<&'b [u8], ReasonCode> {
+        let mut lock_id: [u8; 17] = [0u8; 17];
+        lock_id[..12].copy_from_slice(self.device_id);
+        lock_id[12..].copy_from_slice(MQTT_LOCK_ID_SUFFIX.as_bytes());
+
+        let mut sensor_id: [u8; 19] = [0u8; 19];
+        sensor_id[..12].copy_from_slice(self.device_id);
+        sensor_id[12..].copy_from_slice(MQTT_SENSOR_ID_SUFFIX.as_bytes());
+
+        let mut cover_id: [u8; 18] = [0u8; 18];
+        cover_id[..12].copy_from_slice(self.device_id);
+        cover_id[12..].copy_from_slice(MQTT_COVER_ID_SUFFIX.as_bytes());
+
+        let mut vehicle_id: [u8; 20] = [0u8; 20];
+        vehicle_id[..12].copy_from_slice(self.device_id);
+        vehicle_id[12..].copy_from_slice(MQTT_VEHICLE_ID_SUFFIX.as_bytes());
+
+        let mut rssi_id: [u8; 17] = [0u8; 17];
+        rssi_id[..12].copy_from_slice(self.device_id);
+        rssi_id[12..].copy_from_slice(MQTT_RSSI_ID_SUFFIX.as_bytes());
+
+        let mut discovery_payload = Discovery::new(
+            self.device_name,
+            str::from_utf8(self.device_id).unwrap(),
+            str::from_utf8(&lock_id).unwrap(),
+            str::from_utf8(&sensor_id).unwrap(),
+            str::from_utf8(&self.availability_topic).unwrap(),
+            str::from_utf8(&self.lock_state_topic).unwrap(),
+            str::from_utf8(&self.lock_cmd_topic).unwrap(),
+            str::from_utf8(&self.sensor_state_topic).unwrap(),
+        )
+        .with_attributes_topics(
+            str::from_utf8(&self.lock_attributes_topic).unwrap(),
+            str::from_utf8(&self.sensor_attributes_topic).unwrap(),
+        )
+        .with_names(lock_name, door_name);
+        if self.position_sensor {
+            discovery_payload = discovery_payload.with_cover(
+                str::from_utf8(&cover_id).unwrap(),
+                str::from_utf8(&self.cover_position_topic).unwrap(),
+            );
+        }
+        if self.vehicle_sensor {
+            discovery_payload = discovery_payload.with_vehicle(
+                str::from_utf8(&vehicle_id).unwrap(),
+                str::from_utf8(&self.vehicle_state_topic).unwrap(),
+            );
+        }
+        if self.link.is_some() {
+            discovery_payload = discovery_payload.with_rssi(
+                str::from_utf8(&rssi_id).unwrap(),
+                str::from_utf8(&self.rssi_state_topic).unwrap(),
+            );
+        }
+
+        to_json(&discovery_payload, buf, "discovery payload")
+    }
+
     pub async fn run<T: Read + Write>(
         &mut self,
         sock: T,
-        cmd_channel: &Sender<'static, CriticalSectionRawMutex, LockState, 2>,
-        state_sub: &mut Subscriber<'static, CriticalSectionRawMutex, AnyState, 2, 6, 0>,
+        commands: &CommandQueue<CriticalSectionRawMutex, COMMAND_QUEUE_LEN>,
+        state_feed: &mut StateFeed<'_, CriticalSectionRawMutex>,
+        sensors: &Sensors,
     ) -> Result<(), ReasonCode> {
         // subscribe to the lock command topic
         // listen for door state changes
         // listen for lock state changes
         // select across all the above, and handle.
 
+        // Every session after the first follows a lost connection.
+        if self.sessions > 0 {
+            self.keepalive.reconnected();
+        }
+        self.sessions += 1;
+
         let mut config = ClientConfig::<3, _>::new(
             rust_mqtt::client::client_config::MqttVersion::MQTTv5,
             CountingRng(20000),
@@ -162,9 +375,19 @@ impl<'a> MQTTContext<'a> {
         config.add_client_id("doorctrl");
         config.add_username(self.username);
         config.add_password(self.password);
+        let not_available = Availability {
+            state: MQTT_PAYLOAD_NOT_AVAILABLE,
+            uptime_ms: None,
+            reset_reason: None,
+            boot_count: None,
+            keepalive_s: None,
+            power_fail: false,
+        };
+        let mut not_available_json = [0u8; 32];
+        let not_available_json = to_json(&not_available, &mut not_available_json, "availability")?;
         config.add_will(
             str::from_utf8(&self.availability_topic).unwrap(),
-            MQTT_PAYLOAD_NOT_AVAILABLE.as_bytes(),
+            not_available_json,
             false,
         );
         config.max_packet_size = 1024;
@@ -175,107 +398,430 @@ impl<'a> MQTTContext<'a> {
         self.connect(&mut client).await?;
 
         if let Err(e) = client
-            .subscribe_to_topic(str::from_utf8(&self.lock_cmd_topic).unwrap())
+            .subscribe_to_topic(str::from_utf8(&self.cmd_wildcard_topic).unwrap())
+            .await
+        {
+            error!("failed to subscribe to command topics: {}", e);
+            return Err(e);
+        }
+
+        if let Err(e) = client
+            .subscribe_to_topic(str::from_utf8(&self.lock_cmd_legacy_topic).unwrap())
             .await
         {
-            error!("failed to subscribe to lock command topic: {}", e);
+            error!("failed to subscribe to legacy lock command topic: {}", e);
             return Err(e);
         }
 
+        // Bring the broker up to date with anything that changed while we were disconnected.
+        if let Some(snapshot) = state_feed.snapshot() {
+            self.resync(&mut client, &snapshot).await?;
+        }
+        self.publish_rssi(&mut client).await?;
+
+        let names = self.names;
+        let power_fail = self.power_fail;
+        let mut keepalive = self.keepalive_interval();
+        info!("mqtt keepalive interval {}s", keepalive.as_secs());
+        // A deadline rather than a timer started in the select, which every other event would
+        // restart.
+        let mut next_ping = Instant::now() + keepalive;
         loop {
-            let work = select::select3(
+            let work = select::select4(
                 client.receive_message(),
-                state_sub.next_message_pure(),
-                Timer::after(Duration::from_secs(MQTT_KEEPALIVE)),
+                state_feed.next(),
+                select::select3(sensors.next(), names_changed(names), signalled(power_fail)),
+                Timer::at(next_ping),
             )
             .await;
 
             match work {
-                select::Either3::First(Ok((topic, data))) => {
+                select::Either4::First(Ok((topic, data))) => {
                     info!("received command on topic {}: {}", topic, data);
-                    if data == MQTT_PAYLOAD_LOCK.as_bytes() {
+                    if cmd_topic_entity(self.device_id, topic) != Some(MQTT_ENTITY_LOCK) {
+                        error!("received command on unknown topic {}", topic);
+                    } else if data == MQTT_PAYLOAD_LOCK.as_bytes() {
                         info!("received lock command on topic {}: {}", topic, data);
-                        cmd_channel.clear();
-                        cmd_channel.send(LockState::Locked).await;
+                        commands.send(LockAction::Lock, CommandSource::Mqtt, Instant::now());
                     } else if data == MQTT_PAYLOAD_UNLOCK.as_bytes() {
                         info!("received unlock command on topic {}: {}", topic, data);
-                        cmd_channel.clear();
-                        cmd_channel.send(LockState::Unlocked).await;
+                        commands.send(LockAction::Unlock, CommandSource::Mqtt, Instant::now());
+                    } else if data == MQTT_PAYLOAD_OPEN.as_bytes() {
+                        info!("received open command on topic {}: {}", topic, data);
+                        commands.send(LockAction::Open, CommandSource::Mqtt, Instant::now());
                     } else {
                         error!("recieved unknown lock command");
                     }
                 }
-                select::Either3::First(Err(e)) => {
+                select::Either4::First(Err(e)) => {
                     error!("error receiving from mqtt: {}", e);
                     return Err(e);
                 }
-                select::Either3::Second(AnyState::LockState(LockState::Locked)) => {
-                    info!("sending door locked to mqtt");
-                    if let Err(e) = client
-                        .send_message(
-                            str::from_utf8(&self.lock_state_topic).unwrap(),
-                            MQTT_STATE_LOCKED.as_bytes(),
-                            QualityOfService::QoS1,
-                            false,
-                        )
-                        .await
-                    {
-                        error!("failed to send locked state payload: {}", e);
-                        return Err(e);
-                    }
+                select::Either4::Second(StateEvent::Changed(update)) => {
+                    self.publish_state(&mut client, update.seq, update.state)
+                        .await?;
                 }
-                select::Either3::Second(AnyState::LockState(LockState::Unlocked)) => {
-                    info!("sending door unlocked to mqtt");
-                    if let Err(e) = client
-                        .send_message(
-                            str::from_utf8(&self.lock_state_topic).unwrap(),
-                            MQTT_STATE_UNLOCKED.as_bytes(),
-                            QualityOfService::QoS1,
-                            false,
-                        )
-                        .await
-                    {
-                        error!("failed to send unlocked state payload: {}", e);
-                        return Err(e);
-                    }
+                select::Either4::Second(StateEvent::Resync(snapshot)) => {
+                    self.resync(&mut client, &snapshot).await?;
                 }
-                select::Either3::Second(AnyState::DoorState(DoorState::Open)) => {
-                    info!("sending door open to mqtt");
-                    if let Err(e) = client
-                        .send_message(
-                            str::from_utf8(&self.sensor_state_topic).unwrap(),
-                            MQTT_STATE_ON.as_bytes(),
-                            QualityOfService::QoS1,
-                            false,
-                        )
-                        .await
-                    {
-                        error!("failed to send door state open payload: {}", e);
-                        return Err(e);
-                    }
+                select::Either4::Third(select::Either3::First(reading)) => {
+                    self.publish_reading(&mut client, reading).await?;
                 }
-                select::Either3::Second(AnyState::DoorState(DoorState::Closed)) => {
-                    info!("sending door closed to mqtt");
-                    if let Err(e) = client
-                        .send_message(
-                            str::from_utf8(&self.sensor_state_topic).unwrap(),
-                            MQTT_STATE_OFF.as_bytes(),
-                            QualityOfService::QoS1,
-                            false,
-                        )
-                        .await
-                    {
-                        error!("failed to send door state closed payload: {}", e);
-                        return Err(e);
-                    }
+                select::Either4::Third(select::Either3::Second(())) => {
+                    info!("friendly names changed, republishing discovery");
+                    self.send_discovery(&mut client).await?;
+                }
+                select::Either4::Third(select::Either3::Third(())) => {
+                    warn!("power failing, reporting offline");
+                    self.power_failed = true;
+                    self.send_power_fail(&mut client).await?;
                 }
-                select::Either3::Third(_) => {
+                select::Either4::Fourth(_) => {
                     if let Err(e) = client.send_ping().await {
                         error!("error sending pingL {}", e);
                         return Err(e);
                     }
+                    self.keepalive.pinged();
+                    self.publish_rssi(&mut client).await?;
+
+                    let interval = self.keepalive_interval();
+                    if interval != keepalive {
+                        info!("mqtt keepalive interval now {}s", interval.as_secs());
+                        keepalive = interval;
+                    }
+                    next_ping = Instant::now() + keepalive;
+                }
+            }
+        }
+    }
+
+    /// Publish every state in `snapshot`.
+    async fn resync<T: Read + Write>(
+        &self,
+        client: &mut MqttClient<'_, T, 3, CountingRng>,
+        snapshot: &StateSnapshot,
+    ) -> Result<(), ReasonCode> {
+        for state in snapshot.states() {
+            self.publish_state(client, snapshot.seq, state).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn publish_reading<T: Read + Write>(
+        &self,
+        client: &mut MqttClient<'_, T, 3, CountingRng>,
+        reading: SensorReading,
+    ) -> Result<(), ReasonCode> {
+        let mut position_payload = [0u8; 3];
+        let (topic, payload) = match reading {
+            SensorReading::DoorPosition(position) => {
+                let payload = to_json(&position, &mut position_payload, "door position")?;
+                (&self.cover_position_topic[..], payload)
+            }
+            SensorReading::VehiclePresent(true) => {
+                (&self.vehicle_state_topic[..], MQTT_STATE_ON.as_bytes())
+            }
+            SensorReading::VehiclePresent(false) => {
+                (&self.vehicle_state_topic[..], MQTT_STATE_OFF.as_bytes())
+            }
+        };
+
+        info!(
+            "sending sensor reading {} to mqtt",
+            str::from_utf8(payload).unwrap()
+        );
+        if let Err(e) = client
+            .send_message(
+                str::from_utf8(topic).unwrap(),
+                payload,
+                QualityOfService::QoS1,
+                true,
+            )
+            .await
+        {
+            error!("failed to send sensor reading: {}", e);
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// Report the device offline because mains power has been lost.
+    async fn send_power_fail<T: Read + Write>(
+        &self,
+        client: &mut MqttClient<'_, T, 3, CountingRng>,
+    ) -> Result<(), ReasonCode> {
+        let availability = Availability {
+            state: MQTT_PAYLOAD_NOT_AVAILABLE,
+            uptime_ms: Some(Instant::now().as_millis()),
+            reset_reason: None,
+            boot_count: None,
+            keepalive_s: None,
+            power_fail: true,
+        };
+        let mut availability_json = [0u8; 96];
+        let availability_json = to_json(&availability, &mut availability_json, "availability")?;
+        if let Err(e) = client
+            .send_message(
+                str::from_utf8(&self.availability_topic).unwrap(),
+                availability_json,
+                QualityOfService::QoS1,
+                true,
+            )
+            .await
+        {
+            error!("failed to send power fail availability: {}", e);
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// Publish the WiFi signal strength, if known. Sent with each ping, so it is at most a
+    /// keepalive interval old.
+    async fn publish_rssi<T: Read + Write>(
+        &self,
+        client: &mut MqttClient<'_, T, 3, CountingRng>,
+    ) -> Result<(), ReasonCode> {
+        let Some(rssi) = self.link.and_then(|link| link.rssi()) else {
+            return Ok(());
+        };
+
+        let mut rssi_payload = [0u8; 4];
+        let rssi_payload = to_json(&rssi, &mut rssi_payload, "wifi signal strength")?;
+        if let Err(e) = client
+            .send_message(
+                str::from_utf8(&self.rssi_state_topic).unwrap(),
+                rssi_payload,
+                QualityOfService::QoS1,
+                false,
+            )
+            .await
+        {
+            error!("failed to send wifi signal strength: {}", e);
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    async fn publish_state<T: Read + Write>(
+        &self,
+        client: &mut MqttClient<'_, T, 3, CountingRng>,
+        seq: u32,
+        state: AnyState,
+    ) -> Result<(), ReasonCode> {
+        let (state_topic, attributes_topic, payload, source) = match state {
+            AnyState::LockState(LockState::Locked) => (
+                &self.lock_state_topic[..],
+                &self.lock_attributes_topic[..],
+                MQTT_STATE_LOCKED,
+                MQTT_SOURCE_LOCK,
+            ),
+            AnyState::LockState(LockState::Unlocked) => (
+                &self.lock_state_topic[..],
+                &self.lock_attributes_topic[..],
+                MQTT_STATE_UNLOCKED,
+                MQTT_SOURCE_LOCK,
+            ),
+            AnyState::LockState(LockState::Locking) => (
+                &self.lock_state_topic[..],
+                &self.lock_attributes_topic[..],
+                MQTT_STATE_LOCKING,
+                MQTT_SOURCE_LOCK,
+            ),
+            AnyState::LockState(LockState::Unlocking) => (
+                &self.lock_state_topic[..],
+                &self.lock_attributes_topic[..],
+                MQTT_STATE_UNLOCKING,
+                MQTT_SOURCE_LOCK,
+            ),
+            AnyState::LockState(LockState::Jammed) => (
+                &self.lock_state_topic[..],
+                &self.lock_attributes_topic[..],
+                MQTT_STATE_JAMMED,
+                MQTT_SOURCE_LOCK,
+            ),
+            AnyState::DoorState(DoorState::Open | DoorState::Ajar) => (
+                &self.sensor_state_topic[..],
+                &self.sensor_attributes_topic[..],
+                MQTT_STATE_ON,
+                MQTT_SOURCE_REED,
+            ),
+            AnyState::DoorState(DoorState::Closed) => (
+                &self.sensor_state_topic[..],
+                &self.sensor_attributes_topic[..],
+                MQTT_STATE_OFF,
+                MQTT_SOURCE_REED,
+            ),
+        };
+
+        info!("sending {} state {} to mqtt", source, payload);
+        if let Err(e) = client
+            .send_message(
+                str::from_utf8(state_topic).unwrap(),
+                payload.as_bytes(),
+                QualityOfService::QoS1,
+                false,
+            )
+            .await
+        {
+            error!("failed to send {} state payload: {}", source, e);
+            return Err(e);
+        }
+
+        let attributes = StateAttributes {
+            state: payload,
+            uptime_ms: Instant::now().as_millis(),
+            seq,
+            source,
+            position: match state {
+                AnyState::DoorState(door_state) => Some(door_state.position()),
+                AnyState::LockState(_) => None,
+            },
+        };
+
+        let mut attributes_json = [0u8; 128];
+        let attributes_json = to_json(&attributes, &mut attributes_json, "state attributes")?;
+        if let Err(e) = client
+            .send_message(
+                str::from_utf8(attributes_topic).unwrap(),
+                attributes_json,
+                QualityOfService::QoS1,
+                true,
+            )
+            .await
+        {
+            error!("failed to send {} state attributes: {}", source, e);
+            return Err(e);
+        }
+
+        Ok(())
+    }
+}
+
+// Serialize `value` into `buf`. A payload too large for its buffer ends the session with an error
+// rather than a panic, which would stop the door task along with everything else.
+fn to_json<'b, S: Serialize>(
+    value: &S,
+    buf: &'b mut [u8],
+    what: &str,
+) -> Result<&'b [u8], ReasonCode> {
+    match to_slice(value, buf) {
+        Ok(len) => Ok(&buf[..len]),
+        Err(_) => {
+            error!("{} too large to serialize", what);
+            Err(ReasonCode::BuffError)
+        }
+    }
+}
+
+// Resolves when `signal` is signalled, or never if there is none.
+async fn signalled(signal: Option<&Signal<CriticalSectionRawMutex, ()>>) {
+    match signal {
+        Some(signal) => signal.wait().await,
+        None => core::future::pending().await,
+    }
+}
+
+// Resolves when the friendly names change, or never if they aren't being watched.
+async fn names_changed(names: Option<&FriendlyNames>) {
+    match names {
+        Some(names) => names.changed().await,
+        None => core::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::{block_on, yield_now};
+    use embassy_sync::pubsub::PubSubChannel;
+
+    use super::*;
+    use crate::state::{StateUpdate, StateWatch};
+    use crate::test_support::{Broker, advance};
+
+    #[test]
+    fn test_largest_discovery_fits() {
+        // Every entity, and names as long as they can be configured. Quotes are escaped, so take
+        // up twice the room.
+        let name = str::from_utf8(&[b'"'; 64]).unwrap();
+        let link = LinkQuality::new();
+        let context = MQTTContext::new(b"aabbccddeeff", name, "", "")
+            .with_position_sensor()
+            .with_vehicle_sensor()
+            .with_link_quality(&link);
+
+        let mut json = [0u8; DISCOVERY_LEN];
+        assert!(context.discovery_json(name, name, &mut json).is_ok());
+    }
+    #[test]
+    fn test_power_fail_survives_reconnect() {
+        let power_fail = Signal::new();
+        let commands = CommandQueue::new();
+        let updates = PubSubChannel::<CriticalSectionRawMutex, StateUpdate, 2, 6, 0>::new();
+        let snapshot = StateWatch::new();
+        let sensors = Sensors::new();
+        let mut context =
+            MQTTContext::new(b"aabbccddeeff", "Front door", "", "").with_power_fail(&power_fail);
+
+        for session in 0..2 {
+            let broker = Broker::new();
+            let mut feed = StateFeed::new(updates.subscriber().unwrap(), &snapshot);
+
+            let scenario = async {
+                let mut availability = broker.published_to("doorctl/aabbccddeeff/avail").await;
+                if session == 0 {
+                    let online = str::from_utf8(&availability).unwrap();
+                    assert!(online.contains(r#""state":"online""#), "{}", online);
+
+                    power_fail.signal(());
+                    availability = broker.published_to("doorctl/aabbccddeeff/avail").await;
                 }
+
+                let offline = str::from_utf8(&availability).unwrap();
+                assert!(offline.contains(r#""state":"offline""#), "{}", offline);
+                assert!(offline.contains(r#""power_fail":true"#), "{}", offline);
+            };
+
+            // The scenario finishing ends the session, and the next one is a reconnect.
+            let mqtt = context.run(broker.connection(), &commands, &mut feed, &sensors);
+            if let select::Either::First(result) = block_on(select::select(mqtt, scenario)) {
+                panic!("mqtt session ended: {:?}", result);
             }
         }
     }
+
+    #[test]
+    fn test_ping_between_readings() {
+        let commands = CommandQueue::new();
+        let updates = PubSubChannel::<CriticalSectionRawMutex, StateUpdate, 2, 6, 0>::new();
+        let snapshot = StateWatch::new();
+        let sensors = Sensors::new();
+        let broker = Broker::new();
+        let mut context = MQTTContext::new(b"aabbccddeeff", "Front door", "", "");
+        let mut feed = StateFeed::new(updates.subscriber().unwrap(), &snapshot);
+
+        let scenario = async {
+            broker.published_to("doorctl/aabbccddeeff/avail").await;
+
+            // Readings more often than the keepalive interval don't hold off the ping.
+            for position in 0..7 {
+                advance(Duration::from_secs(10));
+                sensors.report(SensorReading::DoorPosition(position));
+                broker
+                    .published_to("doorctl/aabbccddeeff/cover/position")
+                    .await;
+                // Let the client finish publishing and wait for the next event.
+                yield_now().await;
+            }
+            assert_eq!(broker.pings(), 1);
+        };
+
+        let mqtt = context.run(broker.connection(), &commands, &mut feed, &sensors);
+        if let select::Either::First(result) = block_on(select::select(mqtt, scenario)) {
+            panic!("mqtt session ended: {:?}", result);
+        }
+    }
 }
@@ -0,0 +1,49 @@
+// Friendly names for the entities advertised to Home Assistant, overriding the defaults ("Lock",
+// "Door") in the discovery payload. Unlike the rest of the configuration they can change without a
+// restart: setting them wakes the MQTT task to publish discovery again.
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_sync::signal::Signal;
+
+use crate::config::ConfigV1Value;
+
+/// Names shared between whoever changes the configuration and the MQTT task.
+pub struct FriendlyNames {
+    // Lock name, then door name. Empty names (or none set) keep the default.
+    names: Mutex<CriticalSectionRawMutex, Cell<Option<(ConfigV1Value, ConfigV1Value)>>>,
+    changed: Signal<CriticalSectionRawMutex, ()>,
+}
+
+impl FriendlyNames {
+    pub const fn new() -> Self {
+        Self {
+            names: Mutex::new(Cell::new(None)),
+            changed: Signal::new(),
+        }
+    }
+
+    pub fn set(&self, lock_name: ConfigV1Value, door_name: ConfigV1Value) {
+        self.names
+            .lock(|cell| cell.set(Some((lock_name, door_name))));
+        self.changed.signal(());
+    }
+
+    /// The lock and door names, if set. Reading them covers any change waiting to be noticed, so
+    /// names set before discovery is first published don't cause it to be published again.
+    pub fn get(&self) -> Option<(ConfigV1Value, ConfigV1Value)> {
+        self.changed.reset();
+        self.names.lock(|cell| cell.get())
+    }
+
+    /// Wait until the names are next set.
+    pub async fn changed(&self) {
+        self.changed.wait().await
+    }
+}
+
+impl Default for FriendlyNames {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,8 +1,20 @@
+use core::str;
+
 const TOPIC_PREFIX: &str = "doorctl/";
 const MQTT_TOPIC_SUFFIX_AVAILABILITY: &str = "/avail";
-const MQTT_TOPIC_SUFFIX_LOCK_COMMAND: &str = "/lock/cmd/";
+const MQTT_TOPIC_SUFFIX_LOCK_COMMAND: &str = "/lock/cmd";
+// Command topics were originally published with a trailing slash. Still accepted while existing
+// automations migrate.
+const MQTT_TOPIC_SUFFIX_LOCK_COMMAND_LEGACY: &str = "/lock/cmd/";
+const MQTT_TOPIC_SUFFIX_COMMAND_WILDCARD: &str = "/+/cmd";
+const MQTT_TOPIC_COMMAND_LEVEL: &str = "/cmd";
 const MQTT_TOPIC_SUFFIX_LOCK_STATE: &str = "/lock/state";
 const MQTT_TOPIC_SUFFIX_SENSOR_STATE: &str = "/reed/state";
+const MQTT_TOPIC_SUFFIX_LOCK_ATTRIBUTES: &str = "/lock/attr";
+const MQTT_TOPIC_SUFFIX_SENSOR_ATTRIBUTES: &str = "/reed/attr";
+const MQTT_TOPIC_SUFFIX_COVER_POSITION: &str = "/cover/position";
+const MQTT_TOPIC_SUFFIX_VEHICLE_STATE: &str = "/vehicle/state";
+const MQTT_TOPIC_SUFFIX_RSSI_STATE: &str = "/rssi/state";
 const MQTT_TOPIC_DISCOVERY_PREFIX: &str = "homeassistant/device/";
 const MQTT_TOPIC_DISCOVERY_SUFFIX: &str = "/config";
 
@@ -10,10 +22,24 @@ pub const MQTT_TOPIC_SENSOR_STATE_LEN: usize =
     TOPIC_PREFIX.len() + 12 + MQTT_TOPIC_SUFFIX_SENSOR_STATE.len();
 pub const MQTT_TOPIC_LOCK_STATE_LEN: usize =
     TOPIC_PREFIX.len() + 12 + MQTT_TOPIC_SUFFIX_LOCK_STATE.len();
+pub const MQTT_TOPIC_SENSOR_ATTRIBUTES_LEN: usize =
+    TOPIC_PREFIX.len() + 12 + MQTT_TOPIC_SUFFIX_SENSOR_ATTRIBUTES.len();
+pub const MQTT_TOPIC_LOCK_ATTRIBUTES_LEN: usize =
+    TOPIC_PREFIX.len() + 12 + MQTT_TOPIC_SUFFIX_LOCK_ATTRIBUTES.len();
+pub const MQTT_TOPIC_COVER_POSITION_LEN: usize =
+    TOPIC_PREFIX.len() + 12 + MQTT_TOPIC_SUFFIX_COVER_POSITION.len();
+pub const MQTT_TOPIC_VEHICLE_STATE_LEN: usize =
+    TOPIC_PREFIX.len() + 12 + MQTT_TOPIC_SUFFIX_VEHICLE_STATE.len();
+pub const MQTT_TOPIC_RSSI_STATE_LEN: usize =
+    TOPIC_PREFIX.len() + 12 + MQTT_TOPIC_SUFFIX_RSSI_STATE.len();
 pub const MQTT_TOPIC_AVAILABILITY_LEN: usize =
     TOPIC_PREFIX.len() + 12 + MQTT_TOPIC_SUFFIX_AVAILABILITY.len();
 pub const MQTT_TOPIC_LOCK_COMMAND_LEN: usize =
     TOPIC_PREFIX.len() + 12 + MQTT_TOPIC_SUFFIX_LOCK_COMMAND.len();
+pub const MQTT_TOPIC_LOCK_COMMAND_LEGACY_LEN: usize =
+    TOPIC_PREFIX.len() + 12 + MQTT_TOPIC_SUFFIX_LOCK_COMMAND_LEGACY.len();
+pub const MQTT_TOPIC_COMMAND_WILDCARD_LEN: usize =
+    TOPIC_PREFIX.len() + 12 + MQTT_TOPIC_SUFFIX_COMMAND_WILDCARD.len();
 pub const MQTT_TOPIC_DISCOVERY_LEN: usize =
     MQTT_TOPIC_DISCOVERY_PREFIX.len() + 12 + MQTT_TOPIC_DISCOVERY_SUFFIX.len();
 
@@ -47,6 +73,38 @@ pub(super) fn mk_lock_cmd_topic(device_id: &[u8; 12]) -> [u8; MQTT_TOPIC_LOCK_CO
     topic
 }
 
+pub(super) fn mk_lock_cmd_legacy_topic(
+    device_id: &[u8; 12],
+) -> [u8; MQTT_TOPIC_LOCK_COMMAND_LEGACY_LEN] {
+    const SUFFIX: &str = MQTT_TOPIC_SUFFIX_LOCK_COMMAND_LEGACY;
+
+    let mut topic = [0u8; MQTT_TOPIC_LOCK_COMMAND_LEGACY_LEN];
+
+    let prefix_offset: usize = 0;
+    let device_id_offset: usize = TOPIC_PREFIX.len();
+    let suffix_offset: usize = device_id_offset + device_id.len();
+
+    topic[prefix_offset..device_id_offset].copy_from_slice(TOPIC_PREFIX.as_bytes());
+    topic[device_id_offset..suffix_offset].copy_from_slice(device_id);
+    topic[suffix_offset..].copy_from_slice(SUFFIX.as_bytes());
+    topic
+}
+
+pub(super) fn mk_cmd_wildcard_topic(device_id: &[u8; 12]) -> [u8; MQTT_TOPIC_COMMAND_WILDCARD_LEN] {
+    const SUFFIX: &str = MQTT_TOPIC_SUFFIX_COMMAND_WILDCARD;
+
+    let mut topic = [0u8; MQTT_TOPIC_COMMAND_WILDCARD_LEN];
+
+    let prefix_offset: usize = 0;
+    let device_id_offset: usize = TOPIC_PREFIX.len();
+    let suffix_offset: usize = device_id_offset + device_id.len();
+
+    topic[prefix_offset..device_id_offset].copy_from_slice(TOPIC_PREFIX.as_bytes());
+    topic[device_id_offset..suffix_offset].copy_from_slice(device_id);
+    topic[suffix_offset..].copy_from_slice(SUFFIX.as_bytes());
+    topic
+}
+
 pub(super) fn mk_lock_state_topic(device_id: &[u8; 12]) -> [u8; MQTT_TOPIC_LOCK_STATE_LEN] {
     const SUFFIX: &str = MQTT_TOPIC_SUFFIX_LOCK_STATE;
 
@@ -75,6 +133,80 @@ pub(super) fn mk_sensor_state_topic(device_id: &[u8; 12]) -> [u8; MQTT_TOPIC_SEN
     topic
 }
 
+pub(super) fn mk_lock_attributes_topic(
+    device_id: &[u8; 12],
+) -> [u8; MQTT_TOPIC_LOCK_ATTRIBUTES_LEN] {
+    const SUFFIX: &str = MQTT_TOPIC_SUFFIX_LOCK_ATTRIBUTES;
+
+    let mut topic = [0u8; MQTT_TOPIC_LOCK_ATTRIBUTES_LEN];
+    let prefix_offset: usize = 0;
+    let device_id_offset: usize = TOPIC_PREFIX.len();
+    let suffix_offset: usize = device_id_offset + device_id.len();
+
+    topic[prefix_offset..device_id_offset].copy_from_slice(TOPIC_PREFIX.as_bytes());
+    topic[device_id_offset..suffix_offset].copy_from_slice(device_id);
+    topic[suffix_offset..].copy_from_slice(SUFFIX.as_bytes());
+    topic
+}
+
+pub(super) fn mk_sensor_attributes_topic(
+    device_id: &[u8; 12],
+) -> [u8; MQTT_TOPIC_SENSOR_ATTRIBUTES_LEN] {
+    const SUFFIX: &str = MQTT_TOPIC_SUFFIX_SENSOR_ATTRIBUTES;
+
+    let mut topic = [0u8; MQTT_TOPIC_SENSOR_ATTRIBUTES_LEN];
+    let prefix_offset: usize = 0;
+    let device_id_offset: usize = TOPIC_PREFIX.len();
+    let suffix_offset: usize = device_id_offset + device_id.len();
+
+    topic[prefix_offset..device_id_offset].copy_from_slice(TOPIC_PREFIX.as_bytes());
+    topic[device_id_offset..suffix_offset].copy_from_slice(device_id);
+    topic[suffix_offset..].copy_from_slice(SUFFIX.as_bytes());
+    topic
+}
+
+pub(super) fn mk_cover_position_topic(device_id: &[u8; 12]) -> [u8; MQTT_TOPIC_COVER_POSITION_LEN] {
+    const SUFFIX: &str = MQTT_TOPIC_SUFFIX_COVER_POSITION;
+
+    let mut topic = [0u8; MQTT_TOPIC_COVER_POSITION_LEN];
+    let prefix_offset: usize = 0;
+    let device_id_offset: usize = TOPIC_PREFIX.len();
+    let suffix_offset: usize = device_id_offset + device_id.len();
+
+    topic[prefix_offset..device_id_offset].copy_from_slice(TOPIC_PREFIX.as_bytes());
+    topic[device_id_offset..suffix_offset].copy_from_slice(device_id);
+    topic[suffix_offset..].copy_from_slice(SUFFIX.as_bytes());
+    topic
+}
+
+pub(super) fn mk_vehicle_state_topic(device_id: &[u8; 12]) -> [u8; MQTT_TOPIC_VEHICLE_STATE_LEN] {
+    const SUFFIX: &str = MQTT_TOPIC_SUFFIX_VEHICLE_STATE;
+
+    let mut topic = [0u8; MQTT_TOPIC_VEHICLE_STATE_LEN];
+    let prefix_offset: usize = 0;
+    let device_id_offset: usize = TOPIC_PREFIX.len();
+    let suffix_offset: usize = device_id_offset + device_id.len();
+
+    topic[prefix_offset..device_id_offset].copy_from_slice(TOPIC_PREFIX.as_bytes());
+    topic[device_id_offset..suffix_offset].copy_from_slice(device_id);
+    topic[suffix_offset..].copy_from_slice(SUFFIX.as_bytes());
+    topic
+}
+
+pub(super) fn mk_rssi_state_topic(device_id: &[u8; 12]) -> [u8; MQTT_TOPIC_RSSI_STATE_LEN] {
+    const SUFFIX: &str = MQTT_TOPIC_SUFFIX_RSSI_STATE;
+
+    let mut topic = [0u8; MQTT_TOPIC_RSSI_STATE_LEN];
+    let prefix_offset: usize = 0;
+    let device_id_offset: usize = TOPIC_PREFIX.len();
+    let suffix_offset: usize = device_id_offset + device_id.len();
+
+    topic[prefix_offset..device_id_offset].copy_from_slice(TOPIC_PREFIX.as_bytes());
+    topic[device_id_offset..suffix_offset].copy_from_slice(device_id);
+    topic[suffix_offset..].copy_from_slice(SUFFIX.as_bytes());
+    topic
+}
+
 pub(super) fn mk_discovery_topic(device_id: &[u8; 12]) -> [u8; MQTT_TOPIC_DISCOVERY_LEN] {
     const LEN: usize = MQTT_TOPIC_DISCOVERY_PREFIX.len() + 12 + MQTT_TOPIC_DISCOVERY_SUFFIX.len();
     let mut topic = [0u8; LEN];
@@ -88,3 +220,78 @@ pub(super) fn mk_discovery_topic(device_id: &[u8; 12]) -> [u8; MQTT_TOPIC_DISCOV
     topic[suffix_offset..].copy_from_slice(MQTT_TOPIC_DISCOVERY_SUFFIX.as_bytes());
     topic
 }
+
+/// Strip trailing slashes so `a/b/` and `a/b` are treated as the same topic.
+pub(super) fn normalize_topic(topic: &str) -> &str {
+    topic.trim_end_matches('/')
+}
+
+/// Extract the entity from a command topic of the form `doorctl/<device_id>/<entity>/cmd`.
+///
+/// Returns `None` if the topic is not a command topic for this device.
+pub(super) fn cmd_topic_entity<'t>(device_id: &[u8; 12], topic: &'t str) -> Option<&'t str> {
+    let rest = normalize_topic(topic).strip_prefix(TOPIC_PREFIX)?;
+    let rest = rest.strip_prefix(str::from_utf8(device_id).ok()?)?;
+    let entity = rest
+        .strip_prefix('/')?
+        .strip_suffix(MQTT_TOPIC_COMMAND_LEVEL)?;
+
+    if entity.is_empty() || entity.contains('/') {
+        return None;
+    }
+
+    Some(entity)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEVICE_ID: &[u8; 12] = b"aabbccddeeff";
+
+    #[test]
+    fn test_lock_cmd_topics() {
+        assert_eq!(
+            &mk_lock_cmd_topic(DEVICE_ID),
+            b"doorctl/aabbccddeeff/lock/cmd"
+        );
+        assert_eq!(
+            &mk_lock_cmd_legacy_topic(DEVICE_ID),
+            b"doorctl/aabbccddeeff/lock/cmd/"
+        );
+        assert_eq!(
+            &mk_cmd_wildcard_topic(DEVICE_ID),
+            b"doorctl/aabbccddeeff/+/cmd"
+        );
+    }
+
+    #[test]
+    fn test_cmd_topic_entity() {
+        assert_eq!(
+            cmd_topic_entity(DEVICE_ID, "doorctl/aabbccddeeff/lock/cmd"),
+            Some("lock")
+        );
+        assert_eq!(
+            cmd_topic_entity(DEVICE_ID, "doorctl/aabbccddeeff/lock/cmd/"),
+            Some("lock"),
+            "legacy trailing slash form should be accepted"
+        );
+        assert_eq!(
+            cmd_topic_entity(DEVICE_ID, "doorctl/aabbccddeeff/lock/state"),
+            None
+        );
+        assert_eq!(
+            cmd_topic_entity(DEVICE_ID, "doorctl/112233445566/lock/cmd"),
+            None,
+            "command for another device"
+        );
+        assert_eq!(
+            cmd_topic_entity(DEVICE_ID, "doorctl/aabbccddeeff/cmd"),
+            None
+        );
+        assert_eq!(
+            cmd_topic_entity(DEVICE_ID, "doorctl/aabbccddeeff/a/b/cmd"),
+            None
+        );
+    }
+}
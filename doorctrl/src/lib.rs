@@ -1,6 +1,24 @@
 #![no_std]
 
+pub mod asset;
+pub mod chunked;
+pub mod command;
 pub mod config;
+pub mod counters;
+pub mod distance;
 pub mod door;
+pub mod fallback;
+pub mod flash;
 pub mod hass;
+pub mod reset;
+pub mod sensors;
+pub mod simulate;
+pub mod soak;
 pub mod state;
+pub mod status;
+pub mod udp;
+pub mod url;
+pub mod ws;
+
+#[cfg(test)]
+mod test_support;
@@ -0,0 +1,20 @@
+// Why the device last restarted, reported with its availability so a dashboard can tell a power cut
+// from a crash.
+use serde::Serialize;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, defmt::Format)]
+#[serde(rename_all = "snake_case")]
+pub enum ResetReason {
+    /// Power was applied, or the chip was reset with its enable pin.
+    PowerOn,
+    /// The firmware reset itself, e.g. after a configuration change.
+    Software,
+    /// A watchdog fired, usually because a task hung.
+    Watchdog,
+    /// The supply voltage dropped too low.
+    Brownout,
+    /// Woke from deep sleep.
+    DeepSleep,
+    #[default]
+    Unknown,
+}
@@ -0,0 +1,113 @@
+// Readings from the optional sensors (door position, vehicle presence) on their way to MQTT.
+//
+// Each sensor keeps only its latest reading, so a slow or disconnected consumer sees the current
+// value rather than a backlog.
+use embassy_futures::select;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SensorReading {
+    /// Percentage open, from a distance sensor looking at the door.
+    DoorPosition(u8),
+    /// Whether a vehicle is parked in the garage.
+    VehiclePresent(bool),
+}
+
+pub struct Sensors {
+    door_position: Signal<CriticalSectionRawMutex, u8>,
+    vehicle_present: Signal<CriticalSectionRawMutex, bool>,
+}
+
+impl Sensors {
+    pub const fn new() -> Self {
+        Self {
+            door_position: Signal::new(),
+            vehicle_present: Signal::new(),
+        }
+    }
+
+    pub fn report(&self, reading: SensorReading) {
+        match reading {
+            SensorReading::DoorPosition(position) => self.door_position.signal(position),
+            SensorReading::VehiclePresent(present) => self.vehicle_present.signal(present),
+        }
+    }
+
+    /// Wait for the next reading from any sensor.
+    pub async fn next(&self) -> SensorReading {
+        match select::select(self.door_position.wait(), self.vehicle_present.wait()).await {
+            select::Either::First(position) => SensorReading::DoorPosition(position),
+            select::Either::Second(present) => SensorReading::VehiclePresent(present),
+        }
+    }
+}
+
+impl Default for Sensors {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Decides which sensor to sample next, so a single task can sample each sensor on its own
+/// configured interval.
+pub struct SamplingScheduler<const N: usize> {
+    intervals_ms: [u64; N],
+    next_due_ms: [u64; N],
+}
+
+impl<const N: usize> SamplingScheduler<N> {
+    /// An interval of 0 disables the sensor. Enabled sensors are due immediately.
+    pub fn new(intervals_ms: [u64; N]) -> Self {
+        Self {
+            intervals_ms,
+            next_due_ms: [0; N],
+        }
+    }
+
+    /// The sensor due soonest and when it is due, or `None` if every sensor is disabled.
+    pub fn next(&self) -> Option<(usize, u64)> {
+        (0..N)
+            .filter(|&sensor| self.intervals_ms[sensor] != 0)
+            .map(|sensor| (sensor, self.next_due_ms[sensor]))
+            .min_by_key(|&(_, due_ms)| due_ms)
+    }
+
+    /// Record that `sensor` was sampled at `now_ms`.
+    pub fn sampled(&mut self, sensor: usize, now_ms: u64) {
+        self.next_due_ms[sensor] = now_ms + self.intervals_ms[sensor];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scheduler() {
+        let mut scheduler = SamplingScheduler::new([250, 1000]);
+        assert_eq!(scheduler.next(), Some((0, 0)));
+        scheduler.sampled(0, 0);
+        assert_eq!(scheduler.next(), Some((1, 0)));
+        scheduler.sampled(1, 0);
+
+        let mut samples = [0; 2];
+        while let Some((sensor, due_ms)) = scheduler.next()
+            && due_ms <= 2000
+        {
+            scheduler.sampled(sensor, due_ms);
+            samples[sensor] += 1;
+        }
+
+        assert_eq!(samples, [8, 2]);
+    }
+
+    #[test]
+    fn test_scheduler_disabled() {
+        let mut scheduler = SamplingScheduler::new([0, 500]);
+        assert_eq!(scheduler.next(), Some((1, 0)));
+        scheduler.sampled(1, 0);
+        assert_eq!(scheduler.next(), Some((1, 500)));
+
+        assert_eq!(SamplingScheduler::new([0, 0]).next(), None);
+    }
+}
@@ -0,0 +1,301 @@
+// Simulated reed switches, for testing the web interface and Home Assistant integration against a
+// real device without anyone opening the door.
+//
+// Each reed pin is wrapped in a `SimulatedReed`. While a simulated door state is set, the reeds
+// read as that state would leave them and the real pins are ignored. Clearing it hands the reeds
+// back to the real pins.
+use core::cell::Cell;
+
+use embassy_futures::select;
+use embassy_sync::blocking_mutex::{Mutex, raw::RawMutex};
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Timer};
+use embedded_hal::digital::{ErrorType, InputPin};
+use embedded_hal_async::digital::Wait;
+
+use crate::state::DoorState;
+use crate::url::{form_decode, form_pairs};
+
+/// Most bounces a single request may simulate.
+pub const MAX_REED_BOUNCE: u8 = 50;
+
+/// Time between simulated reed changes while bouncing, comparable to a real reed chattering.
+pub const REED_BOUNCE_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Where a reed is mounted. A reed is grounded when the door is at its position.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReedPosition {
+    Closed,
+    Open,
+}
+
+impl ReedPosition {
+    fn grounded(&self, door_state: DoorState) -> bool {
+        match self {
+            ReedPosition::Closed => door_state == DoorState::Closed,
+            ReedPosition::Open => door_state == DoorState::Open,
+        }
+    }
+}
+
+pub struct DoorSimulator<M: RawMutex> {
+    door_state: Mutex<M, Cell<Option<DoorState>>>,
+    // One signal per reed, as each has its own waiter.
+    closed_reed_edge: Signal<M, ()>,
+    open_reed_edge: Signal<M, ()>,
+}
+
+impl<M: RawMutex> DoorSimulator<M> {
+    pub const fn new() -> Self {
+        Self {
+            door_state: Mutex::new(Cell::new(None)),
+            closed_reed_edge: Signal::new(),
+            open_reed_edge: Signal::new(),
+        }
+    }
+
+    /// Simulate the door being in `door_state`, or return the reeds to the real pins with `None`.
+    pub fn set(&self, door_state: Option<DoorState>) {
+        self.door_state.lock(|cell| cell.set(door_state));
+        self.closed_reed_edge.signal(());
+        self.open_reed_edge.signal(());
+    }
+
+    pub fn door_state(&self) -> Option<DoorState> {
+        self.door_state.lock(|cell| cell.get())
+    }
+
+    /// Bounce the closed reed `bounces` times, as a door swinging shut does, before settling in
+    /// `door_state`.
+    pub async fn bounce(&self, door_state: DoorState, bounces: u8) {
+        let bounced = match door_state {
+            DoorState::Closed => DoorState::Open,
+            DoorState::Open | DoorState::Ajar => DoorState::Closed,
+        };
+
+        for _ in 0..bounces {
+            self.set(Some(bounced));
+            Timer::after(REED_BOUNCE_INTERVAL).await;
+            self.set(Some(door_state));
+            Timer::after(REED_BOUNCE_INTERVAL).await;
+        }
+    }
+
+    fn edge(&self, position: ReedPosition) -> &Signal<M, ()> {
+        match position {
+            ReedPosition::Closed => &self.closed_reed_edge,
+            ReedPosition::Open => &self.open_reed_edge,
+        }
+    }
+}
+
+impl<M: RawMutex> Default for DoorSimulator<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A reed pin that reads as the simulator says while a simulated door state is set.
+pub struct SimulatedReed<'a, P, M: RawMutex> {
+    pin: P,
+    position: ReedPosition,
+    simulator: &'a DoorSimulator<M>,
+}
+
+impl<'a, P: InputPin + Wait, M: RawMutex> SimulatedReed<'a, P, M> {
+    pub fn new(pin: P, position: ReedPosition, simulator: &'a DoorSimulator<M>) -> Self {
+        Self {
+            pin,
+            position,
+            simulator,
+        }
+    }
+
+    async fn wait_until(&mut self, high: bool) -> Result<(), P::Error> {
+        while self.is_high()? != high {
+            self.wait_for_any_edge().await?;
+        }
+        Ok(())
+    }
+}
+
+impl<P: ErrorType, M: RawMutex> ErrorType for SimulatedReed<'_, P, M> {
+    type Error = P::Error;
+}
+
+impl<P: InputPin, M: RawMutex> InputPin for SimulatedReed<'_, P, M> {
+    fn is_high(&mut self) -> Result<bool, Self::Error> {
+        Ok(!self.is_low()?)
+    }
+
+    fn is_low(&mut self) -> Result<bool, Self::Error> {
+        match self.simulator.door_state() {
+            Some(door_state) => Ok(self.position.grounded(door_state)),
+            None => self.pin.is_low(),
+        }
+    }
+}
+
+impl<P: InputPin + Wait, M: RawMutex> Wait for SimulatedReed<'_, P, M> {
+    async fn wait_for_high(&mut self) -> Result<(), Self::Error> {
+        self.wait_until(true).await
+    }
+
+    async fn wait_for_low(&mut self) -> Result<(), Self::Error> {
+        self.wait_until(false).await
+    }
+
+    async fn wait_for_rising_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_low().await?;
+        self.wait_for_high().await
+    }
+
+    async fn wait_for_falling_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_high().await?;
+        self.wait_for_low().await
+    }
+
+    async fn wait_for_any_edge(&mut self) -> Result<(), Self::Error> {
+        let edge = self.simulator.edge(self.position);
+        match select::select(self.pin.wait_for_any_edge(), edge.wait()).await {
+            select::Either::First(result) => result,
+            select::Either::Second(()) => Ok(()),
+        }
+    }
+}
+
+/// A request to the simulation endpoint, parsed from its query string.
+#[derive(Debug, Default, PartialEq)]
+pub struct SimulateRequest {
+    /// `door=open|closed|ajar` simulates the door state, `door=real` ends the simulation. Left
+    /// out, the door stays as it is.
+    pub door: Option<Option<DoorState>>,
+    /// `reed_bounce=n` bounces the reed `n` times before it settles.
+    pub reed_bounce: u8,
+}
+
+impl SimulateRequest {
+    pub fn from_query(query: &str) -> Result<Self, &'static str> {
+        let mut request = Self::default();
+        let mut name_buf = [0u8; 16];
+        let mut value_buf = [0u8; 16];
+
+        for (name, value) in form_pairs(query) {
+            let name = form_decode(name, &mut name_buf)?;
+            let value = form_decode(value, &mut value_buf)?;
+            match name {
+                "door" => {
+                    request.door = Some(match value {
+                        "open" => Some(DoorState::Open),
+                        "closed" => Some(DoorState::Closed),
+                        "ajar" => Some(DoorState::Ajar),
+                        "real" => None,
+                        _ => return Err("door must be open, closed, ajar or real"),
+                    })
+                }
+                "reed_bounce" => {
+                    request.reed_bounce = value.parse().map_err(|_| "invalid reed_bounce")?;
+                    if request.reed_bounce > MAX_REED_BOUNCE {
+                        return Err("reed_bounce too large");
+                    }
+                }
+                _ => return Err("unknown simulation parameter"),
+            }
+        }
+
+        Ok(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::convert::Infallible;
+
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+
+    use super::*;
+
+    struct FixedPin(bool);
+
+    impl ErrorType for FixedPin {
+        type Error = Infallible;
+    }
+
+    impl InputPin for FixedPin {
+        fn is_high(&mut self) -> Result<bool, Self::Error> {
+            Ok(!self.0)
+        }
+
+        fn is_low(&mut self) -> Result<bool, Self::Error> {
+            Ok(self.0)
+        }
+    }
+
+    impl Wait for FixedPin {
+        async fn wait_for_high(&mut self) -> Result<(), Self::Error> {
+            core::future::pending().await
+        }
+
+        async fn wait_for_low(&mut self) -> Result<(), Self::Error> {
+            core::future::pending().await
+        }
+
+        async fn wait_for_rising_edge(&mut self) -> Result<(), Self::Error> {
+            core::future::pending().await
+        }
+
+        async fn wait_for_falling_edge(&mut self) -> Result<(), Self::Error> {
+            core::future::pending().await
+        }
+
+        async fn wait_for_any_edge(&mut self) -> Result<(), Self::Error> {
+            core::future::pending().await
+        }
+    }
+
+    #[test]
+    fn test_simulated_reed() {
+        let simulator = DoorSimulator::<NoopRawMutex>::new();
+        let mut closed = SimulatedReed::new(FixedPin(true), ReedPosition::Closed, &simulator);
+        let mut open = SimulatedReed::new(FixedPin(false), ReedPosition::Open, &simulator);
+        assert_eq!(closed.is_low(), Ok(true), "real pin read until simulated");
+
+        simulator.set(Some(DoorState::Open));
+        embassy_futures::block_on(closed.wait_for_any_edge()).unwrap();
+        assert_eq!(closed.is_low(), Ok(false));
+        assert_eq!(open.is_low(), Ok(true));
+
+        simulator.set(Some(DoorState::Ajar));
+        assert_eq!(closed.is_low(), Ok(false));
+        assert_eq!(open.is_low(), Ok(false));
+
+        simulator.set(None);
+        assert_eq!(closed.is_low(), Ok(true));
+    }
+
+    #[test]
+    fn test_simulate_request() {
+        assert_eq!(
+            SimulateRequest::from_query("door=open&reed_bounce=3"),
+            Ok(SimulateRequest {
+                door: Some(Some(DoorState::Open)),
+                reed_bounce: 3,
+            })
+        );
+        assert_eq!(
+            SimulateRequest::from_query("door=real").unwrap().door,
+            Some(None)
+        );
+        assert_eq!(
+            SimulateRequest::from_query("door=%61jar&reed%5Fbounce=2"),
+            Ok(SimulateRequest {
+                door: Some(Some(DoorState::Ajar)),
+                reed_bounce: 2,
+            })
+        );
+        assert!(SimulateRequest::from_query("door=%zz").is_err());
+        assert!(SimulateRequest::from_query("door=sideways").is_err());
+        assert!(SimulateRequest::from_query("reed_bounce=51").is_err());
+        assert!(SimulateRequest::from_query("pin=2").is_err());
+    }
+}
@@ -0,0 +1,140 @@
+// Leak detection for soak tests. The firmware periodically samples its resource usage and checks
+// it against the first sample taken once the device has settled, so slow leaks show up as steady
+// growth rather than getting lost in normal fluctuation.
+
+/// Resource usage at a point in time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, defmt::Format)]
+pub struct ResourceSample {
+    /// Bytes allocated on the heap.
+    pub heap_used: usize,
+    /// HTTP connections currently being served.
+    pub http_active: usize,
+    /// Commands waiting for the door task.
+    pub cmd_queue: usize,
+    /// State updates waiting for the slowest subscriber.
+    pub state_queue: usize,
+}
+
+/// Change between two samples.
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub struct ResourceDelta {
+    pub heap_used: isize,
+    pub http_active: isize,
+    pub cmd_queue: isize,
+    pub state_queue: isize,
+}
+
+impl ResourceSample {
+    pub fn delta(&self, earlier: &ResourceSample) -> ResourceDelta {
+        ResourceDelta {
+            heap_used: self.heap_used as isize - earlier.heap_used as isize,
+            http_active: self.http_active as isize - earlier.http_active as isize,
+            cmd_queue: self.cmd_queue as isize - earlier.cmd_queue as isize,
+            state_queue: self.state_queue as isize - earlier.state_queue as isize,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SoakLimits {
+    /// Heap growth over the baseline that counts as a leak.
+    pub heap_growth: usize,
+    /// Most HTTP connections that can legitimately be open at once.
+    pub http_active: usize,
+    /// Queue depth that, seen on consecutive samples, means a consumer has stalled.
+    pub queue_depth: usize,
+}
+
+pub struct LeakDetector {
+    limits: SoakLimits,
+    baseline: Option<ResourceSample>,
+    last: Option<ResourceSample>,
+}
+
+impl LeakDetector {
+    pub fn new(limits: SoakLimits) -> Self {
+        Self {
+            limits,
+            baseline: None,
+            last: None,
+        }
+    }
+
+    /// Record a sample, returning its change from the previous one (`None` for the first sample,
+    /// which becomes the baseline) or an error if a limit has been breached.
+    pub fn check(&mut self, sample: ResourceSample) -> Result<Option<ResourceDelta>, &'static str> {
+        let baseline = *self.baseline.get_or_insert(sample);
+        let last = self.last.replace(sample);
+
+        if sample.heap_used > baseline.heap_used + self.limits.heap_growth {
+            return Err("heap grew past soak limit");
+        }
+        if sample.http_active > self.limits.http_active {
+            return Err("more http connections open than workers");
+        }
+
+        if let Some(last) = last {
+            let stalled = |depth: usize, last: usize| {
+                depth >= self.limits.queue_depth && last >= self.limits.queue_depth
+            };
+            if stalled(sample.cmd_queue, last.cmd_queue) {
+                return Err("command queue not draining");
+            }
+            if stalled(sample.state_queue, last.state_queue) {
+                return Err("state queue not draining");
+            }
+        }
+
+        Ok(last.map(|last| sample.delta(&last)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: SoakLimits = SoakLimits {
+        heap_growth: 1024,
+        http_active: 4,
+        queue_depth: 2,
+    };
+
+    fn sample(heap_used: usize, cmd_queue: usize) -> ResourceSample {
+        ResourceSample {
+            heap_used,
+            cmd_queue,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_leak_detector() {
+        let mut detector = LeakDetector::new(LIMITS);
+        assert_eq!(detector.check(sample(10_000, 0)), Ok(None));
+        assert_eq!(
+            detector
+                .check(sample(10_512, 2))
+                .unwrap()
+                .unwrap()
+                .heap_used,
+            512
+        );
+        // Usage may fluctuate as long as it stays near the baseline.
+        assert!(detector.check(sample(10_900, 0)).is_ok());
+        assert!(detector.check(sample(9_000, 0)).is_ok());
+        assert_eq!(
+            detector.check(sample(11_025, 0)),
+            Err("heap grew past soak limit")
+        );
+    }
+
+    #[test]
+    fn test_leak_detector_stalled_queue() {
+        let mut detector = LeakDetector::new(LIMITS);
+        assert!(detector.check(sample(0, 2)).is_ok());
+        assert_eq!(
+            detector.check(sample(0, 2)),
+            Err("command queue not draining")
+        );
+    }
+}
@@ -1,17 +1,318 @@
-#[derive(Copy, Clone)]
+use defmt::warn;
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::pubsub::{ImmediatePublisher, Subscriber, WaitResult};
+use embassy_sync::watch::{self, Watch};
+
+#[derive(Copy, Clone, Debug, PartialEq, defmt::Format)]
 pub enum LockState {
     Locked,
     Unlocked,
+    /// About to lock at the end of a buzz-in.
+    Locking,
+    /// About to unlock for a buzz-in.
+    Unlocking,
+    /// A lock command was refused because the door is open.
+    Jammed,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, defmt::Format)]
 pub enum DoorState {
     Open,
     Closed,
+    /// Neither closed nor fully open. Only reported when a fully open reed is fitted.
+    Ajar,
+}
+
+impl DoorState {
+    /// Approximate position as a percentage open, in the style of an HA cover.
+    pub fn position(&self) -> u8 {
+        match self {
+            DoorState::Closed => 0,
+            DoorState::Ajar => 50,
+            DoorState::Open => 100,
+        }
+    }
 }
 
-#[derive(Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum AnyState {
     LockState(LockState),
     DoorState(DoorState),
 }
+
+/// A state change as published on the state pubsub.
+///
+/// `seq` increases by one with every publication so consumers can tell when they have missed
+/// updates (e.g. the pubsub overflowed while they were busy).
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct StateUpdate {
+    pub seq: u32,
+    pub state: AnyState,
+}
+
+/// All states as of the update numbered `seq`.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct StateSnapshot {
+    pub seq: u32,
+    pub door_state: Option<DoorState>,
+    pub lock_state: Option<LockState>,
+}
+
+impl StateSnapshot {
+    pub fn apply(&mut self, update: StateUpdate) {
+        self.seq = update.seq;
+        match update.state {
+            AnyState::LockState(lock_state) => self.lock_state = Some(lock_state),
+            AnyState::DoorState(door_state) => self.door_state = Some(door_state),
+        }
+    }
+
+    /// The known states, for replaying to a consumer that needs to resync.
+    pub fn states(&self) -> impl Iterator<Item = AnyState> {
+        self.door_state
+            .map(AnyState::DoorState)
+            .into_iter()
+            .chain(self.lock_state.map(AnyState::LockState))
+    }
+}
+
+/// How many tasks can wait on snapshot changes. The visitor light and the counters sync use two;
+/// the rest are spare. Consumers that only need the current snapshot use `try_get` and don't count.
+pub const STATE_WATCH_RECEIVERS: usize = 4;
+
+/// Snapshot of the current states, for consumers that need a complete state.
+pub type StateWatch<M> = Watch<M, StateSnapshot, STATE_WATCH_RECEIVERS>;
+
+/// Publishes state changes with sequence numbers, keeping the snapshot watch up to date.
+pub struct StatePublisher<'a, M: RawMutex> {
+    updates: ImmediatePublisher<'a, M, StateUpdate, 2, 6, 0>,
+    snapshot: watch::Sender<'a, M, StateSnapshot, STATE_WATCH_RECEIVERS>,
+    seq: u32,
+}
+
+impl<'a, M: RawMutex> StatePublisher<'a, M> {
+    pub fn new(
+        updates: ImmediatePublisher<'a, M, StateUpdate, 2, 6, 0>,
+        snapshot: watch::Sender<'a, M, StateSnapshot, STATE_WATCH_RECEIVERS>,
+    ) -> Self {
+        Self {
+            updates,
+            snapshot,
+            seq: 0,
+        }
+    }
+
+    /// Carry on numbering from `seq`, e.g. the last update published before a restart.
+    pub fn with_seq(mut self, seq: u32) -> Self {
+        self.seq = seq;
+        self
+    }
+
+    pub fn publish(&mut self, state: AnyState) {
+        self.seq = self.seq.wrapping_add(1);
+        let update = StateUpdate {
+            seq: self.seq,
+            state,
+        };
+
+        // Update the snapshot first so a consumer that detects a gap on this update can resync
+        // from a snapshot that already includes it.
+        self.snapshot
+            .send_modify(|snapshot| snapshot.get_or_insert_default().apply(update));
+        self.updates.publish_immediate(update);
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Sequence {
+    /// The update follows the last one seen.
+    InOrder,
+    /// One or more updates were missed, the consumer should resync from the snapshot.
+    Gap,
+    /// The update is already covered by a snapshot the consumer resynced from.
+    Stale,
+}
+
+/// Tracks the sequence numbers seen by a consumer of state updates.
+#[derive(Default)]
+pub struct SequenceTracker {
+    last_seq: Option<u32>,
+}
+
+impl SequenceTracker {
+    pub fn check(&mut self, update: &StateUpdate) -> Sequence {
+        let Some(last_seq) = self.last_seq else {
+            self.last_seq = Some(update.seq);
+            return Sequence::InOrder;
+        };
+
+        // Wrapping distance so the comparison survives the counter rolling over.
+        let distance = update.seq.wrapping_sub(last_seq) as i32;
+        if distance <= 0 {
+            return Sequence::Stale;
+        }
+
+        self.last_seq = Some(update.seq);
+        if distance == 1 {
+            Sequence::InOrder
+        } else {
+            Sequence::Gap
+        }
+    }
+
+    pub fn resynced(&mut self, snapshot: &StateSnapshot) {
+        self.last_seq = Some(snapshot.seq);
+    }
+}
+
+/// What a consumer of state changes should send on next.
+#[derive(Debug, PartialEq)]
+pub enum StateEvent {
+    /// The next state change.
+    Changed(StateUpdate),
+    /// Changes were missed. Everything in the snapshot should be sent again.
+    Resync(StateSnapshot),
+}
+
+/// State changes for a single consumer (MQTT, a websocket client, ...), in order and without gaps.
+/// When changes are missed, the consumer is resynced from the snapshot instead.
+pub struct StateFeed<'a, M: RawMutex> {
+    updates: Subscriber<'a, M, StateUpdate, 2, 6, 0>,
+    snapshot: &'a StateWatch<M>,
+    sequence: SequenceTracker,
+}
+
+impl<'a, M: RawMutex> StateFeed<'a, M> {
+    pub fn new(
+        updates: Subscriber<'a, M, StateUpdate, 2, 6, 0>,
+        snapshot: &'a StateWatch<M>,
+    ) -> Self {
+        Self {
+            updates,
+            snapshot,
+            sequence: SequenceTracker::default(),
+        }
+    }
+
+    /// All current states, if any are known yet. Changes from `next` follow on from the snapshot,
+    /// and as the feed is already subscribed none can slip in between.
+    pub fn snapshot(&mut self) -> Option<StateSnapshot> {
+        let snapshot = self.snapshot.try_get()?;
+        self.sequence.resynced(&snapshot);
+        Some(snapshot)
+    }
+
+    pub async fn next(&mut self) -> StateEvent {
+        loop {
+            match self.updates.next_message().await {
+                WaitResult::Message(update) => match self.sequence.check(&update) {
+                    Sequence::InOrder => return StateEvent::Changed(update),
+                    Sequence::Stale => {}
+                    Sequence::Gap => {
+                        warn!("gap in state updates before {}, resyncing", update.seq);
+                        if let Some(snapshot) = self.snapshot() {
+                            return StateEvent::Resync(snapshot);
+                        }
+                    }
+                },
+                WaitResult::Lagged(missed) => {
+                    warn!("missed {} state updates, resyncing", missed);
+                    if let Some(snapshot) = self.snapshot() {
+                        return StateEvent::Resync(snapshot);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+    use embassy_sync::pubsub::PubSubChannel;
+
+    use super::*;
+
+    fn update(seq: u32) -> StateUpdate {
+        StateUpdate {
+            seq,
+            state: AnyState::DoorState(DoorState::Open),
+        }
+    }
+
+    #[test]
+    fn test_sequence_tracker() {
+        let mut tracker = SequenceTracker::default();
+        assert_eq!(tracker.check(&update(5)), Sequence::InOrder);
+        assert_eq!(tracker.check(&update(6)), Sequence::InOrder);
+        assert_eq!(tracker.check(&update(9)), Sequence::Gap);
+        assert_eq!(tracker.check(&update(8)), Sequence::Stale);
+
+        let snapshot = StateSnapshot {
+            seq: 12,
+            ..Default::default()
+        };
+        tracker.resynced(&snapshot);
+        assert_eq!(tracker.check(&update(11)), Sequence::Stale);
+        assert_eq!(tracker.check(&update(12)), Sequence::Stale);
+        assert_eq!(tracker.check(&update(13)), Sequence::InOrder);
+    }
+
+    #[test]
+    fn test_sequence_tracker_wraps() {
+        let mut tracker = SequenceTracker::default();
+        assert_eq!(tracker.check(&update(u32::MAX)), Sequence::InOrder);
+        assert_eq!(tracker.check(&update(0)), Sequence::InOrder);
+        assert_eq!(tracker.check(&update(u32::MAX)), Sequence::Stale);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut snapshot = StateSnapshot::default();
+        assert_eq!(snapshot.states().count(), 0);
+
+        snapshot.apply(StateUpdate {
+            seq: 3,
+            state: AnyState::LockState(LockState::Locked),
+        });
+        snapshot.apply(update(4));
+
+        assert_eq!(snapshot.seq, 4);
+        assert!(matches!(snapshot.lock_state, Some(LockState::Locked)));
+        assert!(matches!(snapshot.door_state, Some(DoorState::Open)));
+        assert_eq!(snapshot.states().count(), 2);
+    }
+
+    #[test]
+    fn test_state_feed() {
+        let channel = PubSubChannel::<NoopRawMutex, StateUpdate, 2, 6, 0>::new();
+        let watch = StateWatch::<NoopRawMutex>::new();
+        let mut publisher = StatePublisher::new(channel.immediate_publisher(), watch.sender());
+        let mut feed = StateFeed::new(channel.subscriber().unwrap(), &watch);
+        assert_eq!(feed.snapshot(), None);
+
+        let closed = AnyState::DoorState(DoorState::Closed);
+        publisher.publish(closed);
+        assert_eq!(
+            block_on(feed.next()),
+            StateEvent::Changed(StateUpdate {
+                seq: 1,
+                state: closed
+            })
+        );
+
+        // More changes than the channel holds.
+        publisher.publish(AnyState::LockState(LockState::Unlocked));
+        publisher.publish(AnyState::DoorState(DoorState::Open));
+        publisher.publish(AnyState::LockState(LockState::Locked));
+        assert_eq!(
+            block_on(feed.next()),
+            StateEvent::Resync(StateSnapshot {
+                seq: 4,
+                door_state: Some(DoorState::Open),
+                lock_state: Some(LockState::Locked),
+            })
+        );
+    }
+}
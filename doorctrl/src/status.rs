@@ -0,0 +1,64 @@
+// Decides what the status LED shows when more than one thing wants it.
+//
+// Each user of the LED owns a priority band and sets (or clears) the pattern it wants. The LED
+// shows the pattern from the highest band that has one, so a band can be cleared to hand the LED
+// back without knowing what the lower bands were showing.
+
+/// Priority bands, lowest first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, defmt::Format)]
+pub enum StatusBand {
+    /// Device status for whoever is installing or debugging it: setup mode, WiFi, MQTT.
+    Diagnostic,
+    /// Lock state for people at the door.
+    Visitor,
+}
+
+const STATUS_BANDS: usize = 2;
+
+pub struct StatusArbiter<P> {
+    bands: [Option<P>; STATUS_BANDS],
+}
+
+impl<P: Copy> StatusArbiter<P> {
+    pub const fn new() -> Self {
+        Self {
+            bands: [None; STATUS_BANDS],
+        }
+    }
+
+    pub fn set(&mut self, band: StatusBand, pattern: Option<P>) {
+        self.bands[band as usize] = pattern;
+    }
+
+    /// The pattern to show, if any band has one.
+    pub fn current(&self) -> Option<P> {
+        self.bands.iter().rev().find_map(|pattern| *pattern)
+    }
+}
+
+impl<P: Copy> Default for StatusArbiter<P> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_arbiter() {
+        let mut arbiter = StatusArbiter::new();
+        assert_eq!(arbiter.current(), None);
+
+        arbiter.set(StatusBand::Diagnostic, Some("green"));
+        assert_eq!(arbiter.current(), Some("green"));
+
+        arbiter.set(StatusBand::Visitor, Some("red"));
+        arbiter.set(StatusBand::Diagnostic, Some("amber"));
+        assert_eq!(arbiter.current(), Some("red"), "visitor band outranks");
+
+        arbiter.set(StatusBand::Visitor, None);
+        assert_eq!(arbiter.current(), Some("amber"));
+    }
+}
@@ -0,0 +1,257 @@
+// Stand-ins for the pieces the firmware provides on the device, so tests that run async code on
+// the host can link: a defmt logger that discards everything and a clock. Critical sections come
+// from the critical-section crate's std implementation. Also an MQTT broker for the client to talk
+// to.
+extern crate std;
+
+use core::cell::{Cell, RefCell};
+use core::convert::Infallible;
+use core::task::Waker;
+
+use embassy_futures::yield_now;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::Duration;
+use embedded_io_async::{ErrorType, Read, Write};
+use heapless::{Deque, String, Vec};
+
+#[defmt::global_logger]
+struct DiscardLogger;
+
+unsafe impl defmt::Logger for DiscardLogger {
+    fn acquire() {}
+    unsafe fn flush() {}
+    unsafe fn release() {}
+    unsafe fn write(_bytes: &[u8]) {}
+}
+
+#[defmt::panic_handler]
+fn defmt_panic() -> ! {
+    panic!("defmt panic")
+}
+
+defmt::timestamp!("{=u64}", 0);
+
+/// What the clock reads when a test starts.
+pub const NOW: Duration = Duration::from_secs(3600);
+
+std::thread_local! {
+    // How far the test on this thread has moved the clock on from NOW.
+    static ELAPSED: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Move the clock on. Tests run in parallel, each on its own thread, so a test only moves the clock
+/// it sees itself.
+pub fn advance(by: Duration) {
+    ELAPSED.with(|elapsed| elapsed.set(elapsed.get() + by.as_ticks()));
+}
+
+struct TestClock;
+
+impl embassy_time_driver::Driver for TestClock {
+    fn now(&self) -> u64 {
+        NOW.as_ticks() + ELAPSED.with(Cell::get)
+    }
+
+    // Nothing is woken. Tests run their futures with `block_on`, which polls continually, so a
+    // timer fires on the first poll after the clock is moved past it.
+    fn schedule_wake(&self, _at: u64, _waker: &Waker) {}
+}
+
+embassy_time_driver::time_driver_impl!(static CLOCK: TestClock = TestClock);
+
+/// A message the client published, with the payload cut short if it doesn't fit.
+pub struct Published {
+    pub topic: String<64>,
+    pub payload: Vec<u8, 128>,
+}
+
+/// Just enough of an MQTT 5 broker for one client: connecting, subscribing, publishing with QoS 0
+/// or 1 and pinging are acknowledged, and everything published is passed on to the test.
+pub struct Broker {
+    // Bytes on their way to the client.
+    to_client: RefCell<Deque<u8, 512>>,
+    // Bytes from the client that don't yet make up a whole packet.
+    from_client: RefCell<Vec<u8, 4096>>,
+    published: Channel<NoopRawMutex, Published, 16>,
+    pings: Cell<u32>,
+}
+
+impl Broker {
+    pub fn new() -> Self {
+        Self {
+            to_client: RefCell::new(Deque::new()),
+            from_client: RefCell::new(Vec::new()),
+            published: Channel::new(),
+            pings: Cell::new(0),
+        }
+    }
+
+    /// The client's end of the connection.
+    pub fn connection(&self) -> BrokerConnection<'_> {
+        BrokerConnection { broker: self }
+    }
+
+    /// Deliver a message to the client with QoS 0, as if published by another client.
+    pub fn publish(&self, topic: &str, payload: &[u8]) {
+        let len = 2 + topic.len() + 1 + payload.len();
+        let mut packet = Vec::<u8, 256>::new();
+        packet.push(0x30).unwrap();
+        encode_len(&mut packet, len);
+        packet
+            .extend_from_slice(&(topic.len() as u16).to_be_bytes())
+            .unwrap();
+        packet.extend_from_slice(topic.as_bytes()).unwrap();
+        // No properties.
+        packet.push(0).unwrap();
+        packet.extend_from_slice(payload).unwrap();
+        self.send(&packet);
+    }
+
+    /// Wait for the client to publish to `topic`, skipping messages to other topics.
+    pub async fn published_to(&self, topic: &str) -> Vec<u8, 128> {
+        loop {
+            let published = self.published.receive().await;
+            if published.topic == topic {
+                return published.payload;
+            }
+        }
+    }
+
+    /// How many times the client has pinged.
+    pub fn pings(&self) -> u32 {
+        self.pings.get()
+    }
+
+    fn send(&self, bytes: &[u8]) {
+        let mut to_client = self.to_client.borrow_mut();
+        for b in bytes {
+            to_client.push_back(*b).unwrap();
+        }
+    }
+
+    // Pass as much as fits in `buf` on to the client.
+    fn receive(&self, buf: &mut [u8]) -> usize {
+        let mut to_client = self.to_client.borrow_mut();
+        let mut len = 0;
+        while len < buf.len()
+            && let Some(b) = to_client.pop_front()
+        {
+            buf[len] = b;
+            len += 1;
+        }
+        len
+    }
+
+    // Take the next whole packet the client has written, if there is one.
+    fn next_packet(&self) -> Option<Vec<u8, 4096>> {
+        let mut from_client = self.from_client.borrow_mut();
+        let (len, header_len) = decode_len(from_client.get(1..)?)?;
+        let end = 1 + header_len + len;
+        if from_client.len() < end {
+            return None;
+        }
+
+        let packet = Vec::from_slice(&from_client[..end]).unwrap();
+        let remaining = from_client.len() - end;
+        from_client.rotate_left(end);
+        from_client.truncate(remaining);
+        Some(packet)
+    }
+
+    async fn handle(&self, packet: &[u8]) {
+        let (_, header_len) = decode_len(&packet[1..]).unwrap();
+        let body = &packet[1 + header_len..];
+        match packet[0] >> 4 {
+            // CONNECT: accepted, with no properties.
+            1 => self.send(&[0x20, 3, 0, 0, 0]),
+            // PUBLISH
+            3 => {
+                let qos = (packet[0] >> 1) & 3;
+                let topic_len = u16::from_be_bytes([body[0], body[1]]) as usize;
+                let topic = core::str::from_utf8(&body[2..2 + topic_len]).unwrap();
+                let mut rest = &body[2 + topic_len..];
+                if qos > 0 {
+                    self.send(&[0x40, 4, rest[0], rest[1], 0, 0]);
+                    rest = &rest[2..];
+                }
+                let (properties_len, properties_header_len) = decode_len(rest).unwrap();
+                let payload = &rest[properties_header_len + properties_len..];
+
+                let published = Published {
+                    topic: topic.try_into().unwrap(),
+                    payload: Vec::from_slice(&payload[..payload.len().min(128)]).unwrap(),
+                };
+                self.published.send(published).await;
+            }
+            // SUBSCRIBE: granted at QoS 1.
+            8 => self.send(&[0x90, 4, body[0], body[1], 0, 1]),
+            // PINGREQ
+            12 => {
+                self.pings.set(self.pings.get() + 1);
+                self.send(&[0xd0, 0]);
+            }
+            _ => {}
+        }
+    }
+}
+
+pub struct BrokerConnection<'a> {
+    broker: &'a Broker,
+}
+
+impl ErrorType for BrokerConnection<'_> {
+    type Error = Infallible;
+}
+
+impl Read for BrokerConnection<'_> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        loop {
+            let len = self.broker.receive(buf);
+            if len > 0 {
+                return Ok(len);
+            }
+            yield_now().await;
+        }
+    }
+}
+
+impl Write for BrokerConnection<'_> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.broker
+            .from_client
+            .borrow_mut()
+            .extend_from_slice(buf)
+            .unwrap();
+        while let Some(packet) = self.broker.next_packet() {
+            self.broker.handle(&packet).await;
+        }
+        Ok(buf.len())
+    }
+}
+
+// The variable length integer MQTT uses for lengths. Returns the value and how many bytes it took.
+fn decode_len(bytes: &[u8]) -> Option<(usize, usize)> {
+    let mut len = 0;
+    for (i, b) in bytes.iter().take(4).enumerate() {
+        len |= ((b & 0x7f) as usize) << (7 * i);
+        if b & 0x80 == 0 {
+            return Some((len, i + 1));
+        }
+    }
+    None
+}
+
+fn encode_len<const N: usize>(packet: &mut Vec<u8, N>, mut len: usize) {
+    loop {
+        let mut b = (len & 0x7f) as u8;
+        len >>= 7;
+        if len > 0 {
+            b |= 0x80;
+        }
+        packet.push(b).unwrap();
+        if len == 0 {
+            break;
+        }
+    }
+}
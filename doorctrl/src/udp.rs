@@ -0,0 +1,268 @@
+// Minimal authenticated UDP protocol for latency sensitive local controllers (e.g. a wall panel)
+// that want to lock/unlock without the overhead of TCP and TLS.
+//
+// Request:  "DCTL" | boot_id(4) | counter(4) | command(1) | hmac-sha1(20)
+// Response: "DCTR" | boot_id(4) | counter(4) | result(1)  | hmac-sha1(20)
+//
+// All integers are big endian and the HMAC covers every byte before it. The magics differ, so a
+// signed response can't be sent back to the device as a request. Replay protection comes
+// from the counter, which must increase with every request, and the boot id, which is random per
+// boot so packets captured before a reboot (when the counter resets) are rejected. A client that
+// receives `WrongBoot` should adopt the boot id from the response and retry. Packets that are
+// malformed or fail the HMAC get no response.
+use sha1::{Digest, Sha1};
+
+pub const UDP_CONTROL_PORT: u16 = 7601;
+
+const MAGIC: [u8; 4] = *b"DCTL";
+const RESPONSE_MAGIC: [u8; 4] = *b"DCTR";
+const HMAC_LEN: usize = 20;
+const SHA1_BLOCK_LEN: usize = 64;
+const HEADER_LEN: usize = MAGIC.len() + 4 + 4 + 1;
+
+pub const REQUEST_LEN: usize = HEADER_LEN + HMAC_LEN;
+pub const RESPONSE_LEN: usize = HEADER_LEN + HMAC_LEN;
+
+const CMD_LOCK: u8 = 1;
+const CMD_UNLOCK: u8 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub enum UdpCommand {
+    Lock,
+    Unlock,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub enum UdpResult {
+    Ok = 0,
+    Malformed = 1,
+    BadMac = 2,
+    WrongBoot = 3,
+    Replay = 4,
+    UnknownCommand = 5,
+}
+
+pub(crate) fn hmac_sha1(key: &[u8], data: &[u8]) -> [u8; HMAC_LEN] {
+    let mut block_key = [0u8; SHA1_BLOCK_LEN];
+    if key.len() > SHA1_BLOCK_LEN {
+        block_key[..HMAC_LEN].copy_from_slice(&Sha1::digest(key));
+    } else {
+        block_key[..key.len()].copy_from_slice(key);
+    }
+
+    let mut ipad = [0x36u8; SHA1_BLOCK_LEN];
+    let mut opad = [0x5cu8; SHA1_BLOCK_LEN];
+    for idx in 0..SHA1_BLOCK_LEN {
+        ipad[idx] ^= block_key[idx];
+        opad[idx] ^= block_key[idx];
+    }
+
+    let mut inner = Sha1::new();
+    inner.update(ipad);
+    inner.update(data);
+
+    let mut outer = Sha1::new();
+    outer.update(opad);
+    outer.update(inner.finalize());
+    outer.finalize().into()
+}
+
+// Compare without short circuiting so the time taken doesn't leak how much of the MAC matched.
+fn macs_equal(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter()
+        .zip(b.iter())
+        .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+        == 0
+}
+
+pub struct UdpControl<'a> {
+    key: &'a [u8],
+    boot_id: u32,
+    last_counter: u32,
+}
+
+impl<'a> UdpControl<'a> {
+    pub fn new(key: &'a [u8], boot_id: u32) -> Self {
+        Self {
+            key,
+            boot_id,
+            last_counter: 0,
+        }
+    }
+
+    /// Authenticate and decode a request.
+    ///
+    /// Returns the request's counter (0 if it couldn't be read) so it can be echoed in the response.
+    pub fn receive(&mut self, packet: &[u8]) -> (u32, Result<UdpCommand, UdpResult>) {
+        if packet.len() != REQUEST_LEN || packet[..MAGIC.len()] != MAGIC {
+            return (0, Err(UdpResult::Malformed));
+        }
+
+        let boot_id = u32::from_be_bytes(packet[4..8].try_into().unwrap());
+        let counter = u32::from_be_bytes(packet[8..12].try_into().unwrap());
+
+        let mac = hmac_sha1(self.key, &packet[..HEADER_LEN]);
+        if !macs_equal(&mac, &packet[HEADER_LEN..]) {
+            return (counter, Err(UdpResult::BadMac));
+        }
+
+        if boot_id != self.boot_id {
+            return (counter, Err(UdpResult::WrongBoot));
+        }
+
+        if counter <= self.last_counter {
+            return (counter, Err(UdpResult::Replay));
+        }
+        self.last_counter = counter;
+
+        match packet[12] {
+            CMD_LOCK => (counter, Ok(UdpCommand::Lock)),
+            CMD_UNLOCK => (counter, Ok(UdpCommand::Unlock)),
+            _ => (counter, Err(UdpResult::UnknownCommand)),
+        }
+    }
+
+    /// Sign the response to a request, or None if the request wasn't authenticated and gets no
+    /// response.
+    pub fn response(&self, counter: u32, result: UdpResult) -> Option<[u8; RESPONSE_LEN]> {
+        if matches!(result, UdpResult::Malformed | UdpResult::BadMac) {
+            return None;
+        }
+
+        let mut resp = [0u8; RESPONSE_LEN];
+        resp[..4].copy_from_slice(&RESPONSE_MAGIC);
+        resp[4..8].copy_from_slice(&self.boot_id.to_be_bytes());
+        resp[8..12].copy_from_slice(&counter.to_be_bytes());
+        resp[12] = result as u8;
+
+        let mac = hmac_sha1(self.key, &resp[..HEADER_LEN]);
+        resp[HEADER_LEN..].copy_from_slice(&mac);
+        Some(resp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use hex::encode;
+
+    use super::*;
+
+    const KEY: &[u8] = b"secret";
+    const BOOT_ID: u32 = 0x01020304;
+
+    fn request(key: &[u8], boot_id: u32, counter: u32, cmd: u8) -> [u8; REQUEST_LEN] {
+        let mut req = [0u8; REQUEST_LEN];
+        req[..4].copy_from_slice(&MAGIC);
+        req[4..8].copy_from_slice(&boot_id.to_be_bytes());
+        req[8..12].copy_from_slice(&counter.to_be_bytes());
+        req[12] = cmd;
+        let mac = hmac_sha1(key, &req[..HEADER_LEN]);
+        req[HEADER_LEN..].copy_from_slice(&mac);
+        req
+    }
+
+    #[test]
+    fn test_hmac_sha1() {
+        // RFC 2202 test case 2
+        assert_eq!(
+            encode(hmac_sha1(b"Jefe", b"what do ya want for nothing?")),
+            "effcdf6ae5eb2fa2d27416d5f184df9c259a7c79"
+        );
+        // RFC 2202 test case 6, key longer than the block size
+        assert_eq!(
+            encode(hmac_sha1(
+                &[0xaa; 80],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            )),
+            "aa4ae5e15272d00e95705637ce8a3b55ed402112"
+        );
+    }
+
+    #[test]
+    fn test_receive() {
+        let mut control = UdpControl::new(KEY, BOOT_ID);
+
+        assert_eq!(
+            control.receive(&request(KEY, BOOT_ID, 1, CMD_UNLOCK)),
+            (1, Ok(UdpCommand::Unlock))
+        );
+        assert_eq!(
+            control.receive(&request(KEY, BOOT_ID, 2, CMD_LOCK)),
+            (2, Ok(UdpCommand::Lock))
+        );
+        assert_eq!(
+            control.receive(&request(KEY, BOOT_ID, 2, CMD_UNLOCK)),
+            (2, Err(UdpResult::Replay)),
+            "repeated counter should be rejected"
+        );
+        assert_eq!(
+            control.receive(&request(KEY, BOOT_ID, 3, 9)),
+            (3, Err(UdpResult::UnknownCommand))
+        );
+        assert_eq!(
+            control.receive(&request(KEY, BOOT_ID, 3, CMD_UNLOCK)),
+            (3, Err(UdpResult::Replay)),
+            "authenticated unknown commands still consume the counter"
+        );
+    }
+
+    #[test]
+    fn test_receive_rejects() {
+        let mut control = UdpControl::new(KEY, BOOT_ID);
+
+        assert_eq!(
+            control.receive(&request(b"wrong", BOOT_ID, 1, CMD_UNLOCK)),
+            (1, Err(UdpResult::BadMac))
+        );
+        assert_eq!(
+            control.receive(&request(KEY, BOOT_ID + 1, 1, CMD_UNLOCK)),
+            (1, Err(UdpResult::WrongBoot))
+        );
+        assert_eq!(
+            control.receive(&request(KEY, BOOT_ID, 1, CMD_UNLOCK)[..REQUEST_LEN - 1]),
+            (0, Err(UdpResult::Malformed))
+        );
+
+        let mut tampered = request(KEY, BOOT_ID, 1, CMD_LOCK);
+        tampered[12] = CMD_UNLOCK;
+        assert_eq!(control.receive(&tampered), (1, Err(UdpResult::BadMac)));
+    }
+
+    #[test]
+    fn test_response() {
+        let control = UdpControl::new(KEY, BOOT_ID);
+        let resp = control.response(7, UdpResult::Replay).unwrap();
+
+        assert_eq!(encode(&resp[..HEADER_LEN]), "44435452010203040000000704");
+        assert_eq!(resp[HEADER_LEN..], hmac_sha1(KEY, &resp[..HEADER_LEN])[..]);
+
+        assert!(control.response(7, UdpResult::BadMac).is_none());
+        assert!(control.response(0, UdpResult::Malformed).is_none());
+    }
+
+    #[test]
+    fn test_response_replayed_as_request() {
+        let mut control = UdpControl::new(KEY, BOOT_ID);
+
+        for result in [
+            UdpResult::Ok,
+            UdpResult::WrongBoot,
+            UdpResult::Replay,
+            UdpResult::UnknownCommand,
+        ] {
+            let resp = control.response(u32::MAX, result).unwrap();
+            assert_eq!(control.receive(&resp), (0, Err(UdpResult::Malformed)));
+        }
+
+        // The counter wasn't consumed.
+        assert_eq!(
+            control.receive(&request(KEY, BOOT_ID, 1, CMD_UNLOCK)),
+            (1, Ok(UdpCommand::Unlock))
+        );
+    }
+}
@@ -0,0 +1,252 @@
+use core::str::FromStr;
+
+use heapless::Vec;
+
+fn hex_value(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Decode `%XX` escapes in `input` into `buf`, returning the decoded string.
+pub fn percent_decode<'b>(input: &str, buf: &'b mut [u8]) -> Result<&'b str, &'static str> {
+    decode(input, buf, false)
+}
+
+/// Decode a name or value from an `application/x-www-form-urlencoded` body into `buf`. As well as
+/// `%XX` escapes, forms encode spaces as `+`.
+pub fn form_decode<'b>(input: &str, buf: &'b mut [u8]) -> Result<&'b str, &'static str> {
+    decode(input, buf, true)
+}
+
+/// Split an `application/x-www-form-urlencoded` body into its `name=value` pairs. Names and values
+/// are returned still encoded, see `form_decode`.
+pub fn form_pairs(body: &str) -> impl Iterator<Item = (&str, &str)> {
+    body.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| pair.split_once('=').unwrap_or((pair, "")))
+}
+
+/// Path segments captured by `match_route`, looked up by the name they have in the pattern.
+#[derive(Debug, PartialEq)]
+pub struct PathParams<'p, 'a, const N: usize> {
+    params: Vec<(&'p str, &'a str), N>,
+}
+
+impl<'p, 'a, const N: usize> PathParams<'p, 'a, N> {
+    pub fn get(&self, name: &str) -> Option<&'a str> {
+        self.params
+            .iter()
+            .find(|(param, _)| *param == name)
+            .map(|(_, value)| *value)
+    }
+
+    /// The segment captured as `name`, parsed as a `T`.
+    pub fn parse<T: FromStr>(&self, name: &str) -> Result<T, &'static str> {
+        self.get(name)
+            .ok_or("no such path parameter")?
+            .parse()
+            .map_err(|_| "invalid path parameter")
+    }
+}
+
+/// Match a decoded request path against a route pattern such as `/api/doors/{id}/lock`, where each
+/// `{name}` segment captures one non-empty path segment. Patterns with more than `N` captures never
+/// match.
+pub fn match_route<'p, 'a, const N: usize>(
+    pattern: &'p str,
+    path: &'a str,
+) -> Option<PathParams<'p, 'a, N>> {
+    let mut params = Vec::new();
+    let mut pattern_segments = pattern.split('/');
+    let mut path_segments = path.split('/');
+
+    loop {
+        match (pattern_segments.next(), path_segments.next()) {
+            (None, None) => return Some(PathParams { params }),
+            (Some(expected), Some(segment)) => {
+                match expected
+                    .strip_prefix('{')
+                    .and_then(|name| name.strip_suffix('}'))
+                {
+                    Some(name) if !segment.is_empty() => params.push((name, segment)).ok()?,
+                    Some(_) => return None,
+                    None if expected == segment => {}
+                    None => return None,
+                }
+            }
+            _ => return None,
+        }
+    }
+}
+
+fn decode<'b>(
+    input: &str,
+    buf: &'b mut [u8],
+    plus_as_space: bool,
+) -> Result<&'b str, &'static str> {
+    let input = input.as_bytes();
+    let mut in_offset = 0;
+    let mut out_offset = 0;
+
+    while in_offset < input.len() {
+        if out_offset >= buf.len() {
+            return Err("buffer too small for decoded value");
+        }
+
+        if input[in_offset] == b'%' {
+            if in_offset + 2 >= input.len() {
+                return Err("truncated percent escape");
+            }
+            match (
+                hex_value(input[in_offset + 1]),
+                hex_value(input[in_offset + 2]),
+            ) {
+                (Some(upper), Some(lower)) => buf[out_offset] = upper << 4 | lower,
+                _ => return Err("invalid percent escape"),
+            }
+            in_offset += 3;
+        } else if plus_as_space && input[in_offset] == b'+' {
+            buf[out_offset] = b' ';
+            in_offset += 1;
+        } else {
+            buf[out_offset] = input[in_offset];
+            in_offset += 1;
+        }
+        out_offset += 1;
+    }
+
+    str::from_utf8(&buf[..out_offset]).map_err(|_| "decoded value is not utf-8")
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use std::string::String;
+    use std::vec::Vec;
+
+    use quickcheck::QuickCheck;
+
+    use super::*;
+
+    #[test]
+    fn test_percent_decode() {
+        let mut buf = [0u8; 32];
+        assert_eq!(percent_decode("/state/lock", &mut buf), Ok("/state/lock"));
+        assert_eq!(
+            percent_decode("/config%20page", &mut buf),
+            Ok("/config page")
+        );
+        assert_eq!(percent_decode("a%3Ab%3a", &mut buf), Ok("a:b:"));
+        assert_eq!(percent_decode("%E2%9C%93", &mut buf), Ok("\u{2713}"));
+        assert_eq!(percent_decode("a+b", &mut buf), Ok("a+b"));
+    }
+
+    #[test]
+    fn test_form() {
+        let mut buf = [0u8; 32];
+        let mut pairs = form_pairs("device_name=front+door&wifi_pass=a%26b%3Dc&&flag");
+        assert_eq!(pairs.next(), Some(("device_name", "front+door")));
+        assert_eq!(pairs.next(), Some(("wifi_pass", "a%26b%3Dc")));
+        assert_eq!(pairs.next(), Some(("flag", "")));
+        assert_eq!(pairs.next(), None);
+
+        assert_eq!(form_decode("front+door", &mut buf), Ok("front door"));
+        assert_eq!(form_decode("a%26b%3Dc", &mut buf), Ok("a&b=c"));
+        assert_eq!(form_decode("1%2B1", &mut buf), Ok("1+1"));
+    }
+
+    #[test]
+    fn test_match_route() {
+        let params = match_route::<2>("/api/doors/{id}/{action}", "/api/doors/2/lock").unwrap();
+        assert_eq!(params.get("action"), Some("lock"));
+        assert_eq!(params.parse::<u8>("id"), Ok(2));
+        assert_eq!(params.parse::<u8>("action"), Err("invalid path parameter"));
+        assert_eq!(params.get("door"), None);
+
+        assert!(match_route::<0>("/state/lock", "/state/lock").is_some());
+        assert!(match_route::<1>("/calibrate/{position}", "/calibrate/").is_none());
+        assert!(match_route::<1>("/calibrate/{position}", "/calibrate/open/now").is_none());
+        assert!(match_route::<1>("/calibrate/{position}", "/calibrate").is_none());
+        assert!(
+            match_route::<1>("/api/{a}/{b}", "/api/1/2").is_none(),
+            "too many captures"
+        );
+    }
+
+    #[test]
+    fn test_percent_decode_errors() {
+        let mut buf = [0u8; 4];
+        assert!(percent_decode("abc%2", &mut buf).is_err());
+        assert!(percent_decode("abc%", &mut buf).is_err());
+        assert!(percent_decode("%zz", &mut buf).is_err());
+        assert!(percent_decode("%ff", &mut buf).is_err(), "not utf-8");
+        assert!(
+            percent_decode("abcde", &mut buf).is_err(),
+            "buffer too small"
+        );
+    }
+
+    // Paths and form bodies come from untrusted clients. Decoding anything into a buffer of any
+    // size never panics, and what is decoded fits the buffer and is no longer than the input.
+    #[test]
+    fn test_decode_any_input() {
+        fn prop(input: String, buf_len: u8) -> bool {
+            let mut buf = [0u8; 256];
+            let buf = &mut buf[..buf_len as usize];
+            let decoded = [
+                percent_decode(&input, buf).map(str::len),
+                form_decode(&input, buf).map(str::len),
+            ];
+            decoded
+                .into_iter()
+                .flatten()
+                .all(|len| len <= buf_len as usize && len <= input.len())
+        }
+        QuickCheck::new()
+            .tests(1000)
+            .quickcheck(prop as fn(String, u8) -> bool);
+    }
+
+    // Escaping every byte of a string decodes back to the string.
+    #[test]
+    fn test_decode_escaped() {
+        fn prop(value: String) -> bool {
+            let escaped: String = value
+                .bytes()
+                .map(|byte| std::format!("%{byte:02X}"))
+                .collect();
+            let mut buf = [0u8; 1024];
+            value.len() > buf.len()
+                || (percent_decode(&escaped, &mut buf) == Ok(&value)
+                    && form_decode(&escaped, &mut buf) == Ok(&value))
+        }
+        QuickCheck::new()
+            .tests(1000)
+            .quickcheck(prop as fn(String) -> bool);
+    }
+
+    // Matching any path against a pattern never panics, and matches exactly when each capture
+    // would be one whole, non-empty segment.
+    #[test]
+    fn test_match_route_any_path() {
+        fn prop(segments: Vec<String>) -> bool {
+            let tail = segments.join("/");
+            let path = std::format!("/api/doors/{tail}");
+            let captured = match match_route::<2>("/api/doors/{id}/{action}", &path) {
+                Some(params) => params.get("id").zip(params.get("action")),
+                None => None,
+            };
+            let expected = tail.split_once('/').filter(|(id, action)| {
+                !id.is_empty() && !action.is_empty() && !action.contains('/')
+            });
+            captured == expected
+        }
+        QuickCheck::new()
+            .tests(1000)
+            .quickcheck(prop as fn(Vec<String>) -> bool);
+    }
+}
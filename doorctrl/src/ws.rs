@@ -0,0 +1,269 @@
+// Messages exchanged with web clients over the websocket.
+//
+// Every message starts with its type, followed by at least one byte of payload. Clients start
+// subscribed to every category of message the device sends, and can narrow that with a subscribe
+// message carrying the categories they want as a bit mask.
+use crate::chunked::ChunkAssembler;
+use crate::command::LockAction;
+
+pub const WS_STATE_UPDATE: u8 = 1;
+pub const WS_CONFIG_UPDATE: u8 = 2;
+pub const WS_NOTIFICATION: u8 = 3;
+pub const WS_SUBSCRIBE: u8 = 4;
+/// A configuration update too large for one frame, sent in chunks (see `crate::chunked`).
+pub const WS_CONFIG_CHUNK: u8 = 5;
+/// Record the current distance reading as the door's fully open or closed position.
+pub const WS_CALIBRATE: u8 = 6;
+
+// Message categories a client can subscribe to.
+pub const WS_TOPIC_STATE: u8 = 1 << 0;
+pub const WS_TOPIC_CONFIG: u8 = 1 << 1;
+pub const WS_TOPIC_NOTIFICATION: u8 = 1 << 2;
+pub const WS_TOPIC_ALL: u8 = WS_TOPIC_STATE | WS_TOPIC_CONFIG | WS_TOPIC_NOTIFICATION;
+
+// calibrate payloads
+pub const WS_CALIBRATE_OPEN: u8 = 1;
+pub const WS_CALIBRATE_CLOSED: u8 = 2;
+
+// state update payloads
+pub const WS_LOCK_LOCK: u8 = 1;
+pub const WS_LOCK_UNLOCK: u8 = 2;
+pub const WS_DOOR_OPEN: u8 = 3;
+pub const WS_DOOR_CLOSED: u8 = 4;
+pub const WS_DOOR_AJAR: u8 = 5;
+pub const WS_LOCK_JAMMED: u8 = 6;
+pub const WS_LOCK_LOCKING: u8 = 7;
+pub const WS_LOCK_UNLOCKING: u8 = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub enum CalibratePosition {
+    Open,
+    Closed,
+}
+
+/// What a message from a web client asks for.
+#[derive(Debug, PartialEq)]
+pub enum ClientRequest<'a> {
+    Command(LockAction),
+    /// A configuration update as JSON, whole or assembled from chunks.
+    ConfigUpdate(&'a [u8]),
+    /// The subscription changed. `added` holds the categories newly subscribed to, which the
+    /// client should be caught up on.
+    Subscribed {
+        added: u8,
+    },
+    Calibrate(CalibratePosition),
+    /// A message of a known type that couldn't be acted on, e.g. an unknown lock command or a
+    /// chunked update that doesn't fit. The client can be told why and carry on.
+    Invalid(&'static str),
+}
+
+/// One web client's side of the websocket: what it is subscribed to and any configuration update
+/// it is part way through sending.
+pub struct ClientSession<const N: usize> {
+    topics: u8,
+    config_update: ChunkAssembler<N>,
+}
+
+impl<const N: usize> ClientSession<N> {
+    pub const fn new() -> Self {
+        Self {
+            topics: WS_TOPIC_ALL,
+            config_update: ChunkAssembler::new(),
+        }
+    }
+
+    /// The categories of message the client is subscribed to.
+    pub fn topics(&self) -> u8 {
+        self.topics
+    }
+
+    pub fn subscribed(&self, topic: u8) -> bool {
+        self.topics & topic != 0
+    }
+
+    /// Decode a message from the client. Returns `Ok(None)` for a chunk that doesn't yet complete
+    /// a configuration update, and an error for a message too short or of an unknown type, after
+    /// which the connection should be closed.
+    pub fn receive<'a>(
+        &'a mut self,
+        data: &'a [u8],
+    ) -> Result<Option<ClientRequest<'a>>, &'static str> {
+        let [kind, payload @ ..] = data else {
+            return Err("websocket message too short");
+        };
+        let Some(&first) = payload.first() else {
+            return Err("websocket message too short");
+        };
+
+        let request = match *kind {
+            WS_STATE_UPDATE => match first {
+                WS_LOCK_LOCK => ClientRequest::Command(LockAction::Lock),
+                WS_LOCK_UNLOCK => ClientRequest::Command(LockAction::Unlock),
+                _ => ClientRequest::Invalid("unknown lock command"),
+            },
+            WS_CONFIG_UPDATE => ClientRequest::ConfigUpdate(payload),
+            WS_CONFIG_CHUNK => match self.config_update.push(payload) {
+                Ok(Some(json)) => ClientRequest::ConfigUpdate(json),
+                Ok(None) => return Ok(None),
+                Err(e) => ClientRequest::Invalid(e),
+            },
+            WS_SUBSCRIBE => {
+                let topics = first & WS_TOPIC_ALL;
+                let added = topics & !self.topics;
+                self.topics = topics;
+                ClientRequest::Subscribed { added }
+            }
+            WS_CALIBRATE => match first {
+                WS_CALIBRATE_OPEN => ClientRequest::Calibrate(CalibratePosition::Open),
+                WS_CALIBRATE_CLOSED => ClientRequest::Calibrate(CalibratePosition::Closed),
+                _ => ClientRequest::Invalid("unknown calibration position"),
+            },
+            _ => return Err("unknown websocket message type"),
+        };
+
+        Ok(Some(request))
+    }
+}
+
+impl<const N: usize> Default for ClientSession<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use std::vec::Vec;
+
+    use quickcheck::{QuickCheck, TestResult};
+
+    use super::*;
+    use crate::chunked::{CHUNK_LAST, CHUNK_MORE};
+
+    const UPDATE_LEN: usize = 64;
+
+    #[test]
+    fn test_receive() {
+        let mut session = ClientSession::<UPDATE_LEN>::new();
+        assert_eq!(
+            session.receive(&[WS_STATE_UPDATE, WS_LOCK_UNLOCK]),
+            Ok(Some(ClientRequest::Command(LockAction::Unlock)))
+        );
+        assert_eq!(
+            session.receive(&[WS_CONFIG_CHUNK, CHUNK_MORE, b'{']),
+            Ok(None)
+        );
+        assert_eq!(
+            session.receive(&[WS_CONFIG_CHUNK, CHUNK_LAST, b'}']),
+            Ok(Some(ClientRequest::ConfigUpdate(b"{}")))
+        );
+        assert_eq!(
+            session.receive(&[WS_CALIBRATE, 9]),
+            Ok(Some(ClientRequest::Invalid("unknown calibration position")))
+        );
+        assert!(session.receive(&[WS_SUBSCRIBE]).is_err());
+        assert!(session.receive(&[0, 1]).is_err());
+
+        assert_eq!(
+            session.receive(&[WS_SUBSCRIBE, WS_TOPIC_NOTIFICATION]),
+            Ok(Some(ClientRequest::Subscribed { added: 0 }))
+        );
+        assert!(!session.subscribed(WS_TOPIC_STATE));
+        assert_eq!(
+            session.receive(&[WS_SUBSCRIBE, 0xff]),
+            Ok(Some(ClientRequest::Subscribed {
+                added: WS_TOPIC_STATE | WS_TOPIC_CONFIG
+            }))
+        );
+        assert_eq!(session.topics(), WS_TOPIC_ALL);
+    }
+
+    // Any sequence of messages is either decoded or refused, without panicking. Only messages too
+    // short or of a type clients don't send are refused, and the client is left subscribed only to
+    // categories that exist.
+    #[test]
+    fn test_receive_any_messages() {
+        const CLIENT_TYPES: [u8; 5] = [
+            WS_STATE_UPDATE,
+            WS_CONFIG_UPDATE,
+            WS_SUBSCRIBE,
+            WS_CONFIG_CHUNK,
+            WS_CALIBRATE,
+        ];
+
+        fn prop(messages: Vec<Vec<u8>>) -> bool {
+            let mut session = ClientSession::<UPDATE_LEN>::new();
+            messages.iter().all(|message| {
+                let valid = message.len() >= 2 && CLIENT_TYPES.contains(&message[0]);
+                let topics = session.topics();
+                let ok = match session.receive(message) {
+                    Ok(Some(ClientRequest::Subscribed { added })) => {
+                        added & topics == 0 && added & !WS_TOPIC_ALL == 0
+                    }
+                    Ok(Some(ClientRequest::ConfigUpdate(json)))
+                        if message[0] == WS_CONFIG_UPDATE =>
+                    {
+                        json == &message[1..]
+                    }
+                    Ok(Some(ClientRequest::ConfigUpdate(json))) => json.len() <= UPDATE_LEN,
+                    Ok(_) => true,
+                    Err(_) => !valid,
+                };
+                ok && (valid || session.topics() == topics) && session.topics() & !WS_TOPIC_ALL == 0
+            })
+        }
+        QuickCheck::new()
+            .tests(1000)
+            .quickcheck(prop as fn(Vec<Vec<u8>>) -> bool);
+    }
+
+    // However a configuration update is split into chunks, and whatever subscribe messages are
+    // sent between the chunks, the update is assembled whole and only once its last chunk arrives.
+    #[test]
+    fn test_chunked_update_any_split() {
+        fn prop(update: Vec<u8>, splits: Vec<usize>, subscribe: Vec<u8>) -> TestResult {
+            if update.len() > UPDATE_LEN {
+                return TestResult::discard();
+            }
+
+            let mut ends: Vec<usize> = splits
+                .iter()
+                .map(|split| split % (update.len() + 1))
+                .collect();
+            ends.push(update.len());
+            ends.sort();
+
+            let mut session = ClientSession::<UPDATE_LEN>::new();
+            let mut start = 0;
+            for (i, &end) in ends.iter().enumerate() {
+                if let Some(&topics) = subscribe.get(i)
+                    && session.receive(&[WS_SUBSCRIBE, topics]).is_err()
+                {
+                    return TestResult::failed();
+                }
+
+                let last = i == ends.len() - 1;
+                let mut message =
+                    Vec::from([WS_CONFIG_CHUNK, if last { CHUNK_LAST } else { CHUNK_MORE }]);
+                message.extend_from_slice(&update[start..end]);
+                start = end;
+
+                let received = session.receive(&message);
+                let expected = if last {
+                    Ok(Some(ClientRequest::ConfigUpdate(&update[..])))
+                } else {
+                    Ok(None)
+                };
+                if received != expected {
+                    return TestResult::failed();
+                }
+            }
+            TestResult::passed()
+        }
+        QuickCheck::new()
+            .tests(1000)
+            .quickcheck(prop as fn(Vec<u8>, Vec<usize>, Vec<u8>) -> TestResult);
+    }
+}
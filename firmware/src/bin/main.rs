@@ -17,15 +17,16 @@ use embassy_futures::select;
 use embassy_net::{
     tcp::{
         client::{TcpClient, TcpClientState, TcpConnection},
-        TcpSocket,
+        State, TcpSocket,
     },
+    udp::{PacketMetadata, UdpSocket},
     IpListenEndpoint, Ipv4Cidr, Runner, Stack, StackResources, StaticConfigV4,
 };
 use embassy_sync::{
-    blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, mutex::Mutex,
-    pubsub::PubSubChannel,
+    blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex, pubsub::PubSubChannel,
+    signal::Signal,
 };
-use embassy_time::{Duration, Timer};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
 
 use embedded_nal_async::TcpConnect;
 use embedded_storage::nor_flash::NorFlash;
@@ -39,6 +40,8 @@ use esp_hal::gpio::{Input, InputConfig, Level, Output, OutputConfig, Pull};
 #[cfg(target_arch = "riscv32")]
 use esp_hal::interrupt::software::SoftwareInterruptControl;
 use esp_hal::rng::{Rng, Trng};
+use esp_hal::rtc_cntl::{self, SocResetReason};
+use esp_hal::system::Cpu;
 use esp_hal::timer::timg::TimerGroup;
 
 use esp_radio::{
@@ -51,23 +54,77 @@ use esp_radio::{
 use esp_storage::FlashStorage;
 use heapless::Vec;
 
-use doorctrl::config::{ConfigV1, ConfigV1Value};
-use doorctrl::door::Door;
-use doorctrl::hass::MQTTContext;
-use doorctrl::state::{AnyState, LockState};
-
-use firmware::web::HttpClientHandler;
-use firmware::ws2812::{Light, LightColor, LIGHT_UPDATE, WS2812B};
+use doorctrl::command::{CommandQueue, CommandSource, LockAction, COMMAND_QUEUE_LEN};
+use doorctrl::config::{ConfigV1, ConfigV1Value, CONFIG_FLASH_END};
+use doorctrl::counters::{Counters, COUNTERS_WORDS};
+use doorctrl::distance::{echo_to_mm, position_changed, DistanceFilter, PresenceDetector};
+#[cfg(feature = "power-fail")]
+use doorctrl::door::PowerFailLock;
+use doorctrl::door::{Door, LockPolarity};
+use doorctrl::fallback::NetworkTrial;
+use doorctrl::flash;
+use doorctrl::hass::{keepalive::LinkQuality, names::FriendlyNames, MQTTContext};
+use doorctrl::reset::ResetReason;
+use doorctrl::sensors::{SamplingScheduler, SensorReading, Sensors};
+use doorctrl::simulate::ReedPosition;
+#[cfg(feature = "simulate")]
+use doorctrl::simulate::SimulatedReed;
+#[cfg(feature = "soak")]
+use doorctrl::soak::{LeakDetector, ResourceSample, SoakLimits};
+use doorctrl::state::{LockState, StateFeed, StatePublisher, StateUpdate, StateWatch};
+use doorctrl::udp::{UdpCommand, UdpControl, UdpResult, REQUEST_LEN, UDP_CONTROL_PORT};
+
+use firmware::distance::{self, Hcsr04};
+use firmware::health;
+use firmware::resources::{self, HTTP_WORKERS, SOCKET_NUM};
+use firmware::web::{self, HttpClientHandler, HttpServiceState};
+use firmware::ws2812::{Light, LightColor, LIGHT_UPDATE, VISITOR_LIGHT_UPDATE, WS2812B};
 use firmware::{mk_static, ws2812::LightPattern};
 
-const SOCKET_NUM: usize = 8;
-
-// cmd_channel is for processing incomming command from external sources (i.e. lock/unlock)
-static CMD_CHANNEL: Channel<CriticalSectionRawMutex, LockState, 2> =
-    Channel::<CriticalSectionRawMutex, LockState, 2>::new();
+// How long the visitor light flashes after a refused request.
+const VISITOR_DENIED_TIME: Duration = Duration::from_secs(3);
+#[cfg(feature = "soak")]
+const SOAK_WARMUP: Duration = Duration::from_secs(120);
+#[cfg(feature = "soak")]
+const SOAK_INTERVAL: Duration = Duration::from_secs(60);
+// Longest a restart waits for web requests being served to finish.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(3);
+// How often changed counters are synced from RTC memory to flash.
+const COUNTERS_SYNC_INTERVAL: Duration = Duration::from_secs(3600);
+
+// commands holds lock/unlock commands from external sources until the door task applies them
+static COMMANDS: CommandQueue<CriticalSectionRawMutex, COMMAND_QUEUE_LEN> = CommandQueue::new();
 // state_pubsub is for eminating changes in state as they are detected
-static STATE_PUBSUB: PubSubChannel<CriticalSectionRawMutex, AnyState, 2, 6, 0> =
-    PubSubChannel::<CriticalSectionRawMutex, AnyState, 2, 6, 0>::new();
+static STATE_PUBSUB: PubSubChannel<CriticalSectionRawMutex, StateUpdate, 2, 6, 0> =
+    PubSubChannel::<CriticalSectionRawMutex, StateUpdate, 2, 6, 0>::new();
+// state_snapshot holds the latest of every state, for consumers that need to (re)sync
+static STATE_SNAPSHOT: StateWatch<CriticalSectionRawMutex> = StateWatch::new();
+// sensors carries readings from the optional sensors to MQTT
+static SENSORS: Sensors = Sensors::new();
+// link_quality holds the latest WiFi signal strength, for the MQTT keepalive
+static LINK_QUALITY: LinkQuality = LinkQuality::new();
+// friendly_names holds the entity names for discovery, which the web interface can change live
+static FRIENDLY_NAMES: FriendlyNames = FriendlyNames::new();
+// visitor_denied tells the visitor light that a request from the door was refused
+static VISITOR_DENIED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+// power_fail tells the MQTT task mains power has been lost
+static POWER_FAIL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+// sync_counters asks for the counters to be synced to flash now rather than at the next interval
+static SYNC_COUNTERS: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+// rtc_counters mirrors the counters in RTC fast memory, which keeps its contents through a restart
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static mut RTC_COUNTERS: [u32; COUNTERS_WORDS] = [0; COUNTERS_WORDS];
+
+fn rtc_counters() -> [u32; COUNTERS_WORDS] {
+    // SAFETY: single core, and only accessed from tasks on the one executor.
+    unsafe { core::ptr::addr_of!(RTC_COUNTERS).read_volatile() }
+}
+
+fn set_rtc_counters(counters: &Counters) {
+    // SAFETY: as for rtc_counters.
+    unsafe { core::ptr::addr_of_mut!(RTC_COUNTERS).write_volatile(counters.to_words()) }
+}
 
 #[panic_handler]
 fn panic(_: &core::panic::PanicInfo) -> ! {
@@ -107,6 +164,22 @@ fn mac_to_hex(mac: [u8; 6]) -> [u8; 12] {
 
 type Storage = &'static Mutex<CriticalSectionRawMutex, FlashRegion<'static, FlashStorage<'static>>>;
 
+#[cfg(not(feature = "simulate"))]
+type ReedPin = Input<'static>;
+#[cfg(feature = "simulate")]
+type ReedPin = SimulatedReed<'static, Input<'static>, CriticalSectionRawMutex>;
+
+// Reeds are read through the door simulator when it's built in.
+#[cfg(not(feature = "simulate"))]
+fn reed_pin(pin: Input<'static>, _position: ReedPosition) -> ReedPin {
+    pin
+}
+
+#[cfg(feature = "simulate")]
+fn reed_pin(pin: Input<'static>, position: ReedPosition) -> ReedPin {
+    SimulatedReed::new(pin, position, &web::DOOR_SIMULATOR)
+}
+
 fn prepare_flash(flash: &'static mut FlashStorage<'static>) -> Storage {
     let partition_buf = mk_static!(
         [u8; partitions::PARTITION_TABLE_MAX_LEN],
@@ -149,14 +222,14 @@ async fn main(spawner: Spawner) {
     );
 
     // Init RGB
-    let light = Light {
-        inner: WS2812B::new(
+    let light = Light::new(
+        WS2812B::new(
             peripherals.RMT,
             CpuClock::_80MHz.frequency().as_mhz(),
             peripherals.GPIO8,
         )
         .expect("create LED failed"),
-    };
+    );
     spawner.spawn(blink(light)).expect("failed to spawn blink");
     LIGHT_UPDATE.signal(LightPattern::Solid(LightColor::red()));
 
@@ -164,38 +237,156 @@ async fn main(spawner: Spawner) {
     let flash = mk_static!(FlashStorage, FlashStorage::new(peripherals.FLASH));
     let storage = prepare_flash(flash);
 
+    let mut locked_storage = storage.lock().await;
+    let config = ConfigV1::load(locked_storage.deref_mut());
+    let counters = Counters::restore(
+        rtc_counters(),
+        Counters::load(locked_storage.deref_mut()).ok(),
+    );
+    drop(locked_storage);
+    set_rtc_counters(&counters);
+    info!(
+        "boot {}, continuing from state change {}",
+        counters.boot_count, counters.event_seq
+    );
+    spawner.spawn(counters_sync(storage, counters)).ok();
+
     let rst_pin = Input::new(
         peripherals.GPIO3,
         InputConfig::default().with_pull(Pull::Up),
     );
 
-    // Init the door
-    let lock_pin = Output::new(peripherals.GPIO1, Level::Low, OutputConfig::default());
-    let reed_pin = Input::new(
-        peripherals.GPIO2,
-        InputConfig::default().with_pull(Pull::Up),
+    // Init the door. The pin starts at the locked level so the door doesn't unlock at boot.
+    let lock_polarity = config
+        .as_ref()
+        .map(|cfg| cfg.lock_polarity)
+        .unwrap_or_default();
+    let lock_level = match lock_polarity {
+        LockPolarity::ActiveHigh => Level::Low,
+        LockPolarity::ActiveLow => Level::High,
+    };
+    let lock_pin = Output::new(peripherals.GPIO1, lock_level, OutputConfig::default());
+    let reed_pin = reed_pin(
+        Input::new(
+            peripherals.GPIO2,
+            InputConfig::default().with_pull(Pull::Up),
+        ),
+        ReedPosition::Closed,
     );
     let door = Door::new(
         lock_pin,
         reed_pin,
-        CMD_CHANNEL.receiver(),
-        STATE_PUBSUB.immediate_publisher(),
-    );
+        &COMMANDS,
+        StatePublisher::new(STATE_PUBSUB.immediate_publisher(), STATE_SNAPSHOT.sender())
+            .with_seq(counters.event_seq),
+    )
+    .with_lock_polarity(lock_polarity)
+    .with_lock_while_open(
+        config
+            .as_ref()
+            .map(|cfg| cfg.lock_while_open)
+            .unwrap_or_default(),
+    )
+    .with_startup_grace(Duration::from_millis(
+        config
+            .as_ref()
+            .map(|cfg| cfg.startup_grace_ms)
+            .unwrap_or(1000)
+            .into(),
+    ))
+    .with_buzz_in(Duration::from_secs(
+        config
+            .as_ref()
+            .map(|cfg| cfg.buzz_in_secs)
+            .unwrap_or(5)
+            .into(),
+    ));
+    #[cfg(feature = "dual-reed")]
+    let door = door.with_open_reed(reed_pin(
+        Input::new(
+            peripherals.GPIO4,
+            InputConfig::default().with_pull(Pull::Up),
+        ),
+        ReedPosition::Open,
+    ));
     spawner.spawn(door_service(door)).ok();
 
+    #[cfg(feature = "override-button")]
+    spawner
+        .spawn(override_button(
+            Input::new(
+                peripherals.GPIO9,
+                InputConfig::default().with_pull(Pull::Up),
+            ),
+            Duration::from_millis(
+                config
+                    .as_ref()
+                    .map(|cfg| cfg.override_hold_ms)
+                    .unwrap_or(2000)
+                    .into(),
+            ),
+        ))
+        .ok();
+
+    #[cfg(feature = "power-fail")]
+    spawner
+        .spawn(power_fail(
+            Input::new(
+                peripherals.GPIO0,
+                InputConfig::default().with_pull(Pull::Up),
+            ),
+            config
+                .as_ref()
+                .map(|cfg| cfg.power_fail_lock)
+                .unwrap_or_default(),
+        ))
+        .ok();
+
+    #[cfg(feature = "soak")]
+    spawner.spawn(soak_monitor()).ok();
+
+    #[cfg(feature = "distance-sensor")]
+    let door_sensor = Some(Hcsr04::new(
+        Output::new(peripherals.GPIO5, Level::Low, OutputConfig::default()),
+        Input::new(peripherals.GPIO6, InputConfig::default()),
+    ));
+    #[cfg(not(feature = "distance-sensor"))]
+    let door_sensor = None;
+    #[cfg(feature = "vehicle-sensor")]
+    let vehicle_sensor = Some(Hcsr04::new(
+        Output::new(peripherals.GPIO7, Level::Low, OutputConfig::default()),
+        Input::new(peripherals.GPIO10, InputConfig::default()),
+    ));
+    #[cfg(not(feature = "vehicle-sensor"))]
+    let vehicle_sensor = None;
+
     // Init wifi hardware
     let esp_radio_ctrl = &*mk_static!(Controller<'static>, esp_radio::init().unwrap());
     let (controller, interfaces) =
         esp_radio::wifi::new(esp_radio_ctrl, peripherals.WIFI, Default::default()).unwrap();
 
-    let mut locked_storage = storage.lock().await;
-    let config = ConfigV1::load(locked_storage.deref_mut());
-    drop(locked_storage);
+    spawner
+        .spawn(sensor_sampler(
+            door_sensor,
+            vehicle_sensor,
+            config.as_ref().copied().unwrap_or_default(),
+        ))
+        .ok();
 
     match config {
         Ok(cfg) => {
             info!("config ready, entering normal mode");
-            normal_mode(spawner, cfg, controller, interfaces, storage, rst_pin).await
+            distance::set_calibration(cfg.calibration());
+            normal_mode(
+                spawner,
+                cfg,
+                controller,
+                interfaces,
+                storage,
+                rst_pin,
+                counters.boot_count,
+            )
+            .await
         }
         Err(e) => {
             warn!("config not ready ({}), entering setup mode", e);
@@ -215,6 +406,7 @@ async fn normal_mode(
     interfaces: Interfaces<'static>,
     storage: Storage,
     rst_pin: Input<'static>,
+    boot_count: u32,
 ) {
     if let Err(e) = spawner.spawn(factory_resetter(rst_pin, storage)) {
         error!("error spawning reset monitor: {}", e);
@@ -226,8 +418,22 @@ async fn normal_mode(
     let wifi_interface = interfaces.sta;
     let net_config = embassy_net::Config::dhcpv4(Default::default());
 
+    // A configuration kept to fall back to means these WiFi settings haven't worked yet.
+    let trial =
+        NetworkTrial::new(ConfigV1::load_fallback(storage.lock().await.deref_mut()).is_ok());
+    if trial.pending() {
+        info!("trying new wifi settings");
+    }
+
     spawner
-        .spawn(wifi_client(controller, config.wifi_ssid, config.wifi_pass))
+        .spawn(wifi_client(
+            controller,
+            config.wifi_ssid,
+            config.wifi_pass,
+            config.rssi_interval_secs,
+            storage,
+            trial,
+        ))
         .ok();
 
     let (stack, runner) = embassy_net::new(
@@ -252,32 +458,53 @@ async fn normal_mode(
     stack.wait_config_up().await;
     info!("IP config applied {}", stack.config_v4().unwrap().address);
 
-    if let Err(e) = spawner.spawn(mqtt_service(device_id, config, stack)) {
+    FRIENDLY_NAMES.set(config.lock_name, config.door_name);
+    if let Err(e) = spawner.spawn(mqtt_service(device_id, config, stack, boot_count)) {
         error!("error spanning MQTT client: {}", e);
     }
 
-    let cmd_sender = CMD_CHANNEL.sender();
+    if config.visitor_led {
+        if let Err(e) = spawner.spawn(visitor_light()) {
+            error!("error spawning visitor light: {}", e);
+        }
+    }
+
+    if config.udp_key.as_str().is_empty() {
+        info!("no UDP control key configured, UDP control disabled");
+    } else if let Err(e) = spawner.spawn(udp_control(stack, config.udp_key, rng.random())) {
+        error!("error spawning UDP control: {}", e);
+    }
+
+    let service_state = mk_static!(
+        Mutex<CriticalSectionRawMutex, HttpServiceState>,
+        Mutex::new(HttpServiceState { storage, config })
+    );
 
     let http_server = mk_static!(
         weblite::server::Server::<HttpClientHandler>,
         weblite::server::Server::<_>::new(HttpClientHandler::new(
-            firmware::web::HttpServiceState {
-                storage,
-                config,
-                door_state: None,
-                lock_state: None,
-            },
-            cmd_sender,
+            service_state,
+            &COMMANDS,
             &STATE_PUBSUB,
+            &STATE_SNAPSHOT,
+            &FRIENDLY_NAMES,
         ))
     );
 
-    for _ in 0..4 {
+    let workers = web::set_connection_limit(config.http_max_connections.into());
+    resources::report(workers);
+    for _ in 0..workers {
         info!("starting a web server task");
         if let Err(e) = spawner.spawn(http_connection(stack, http_server)) {
             error!("error spawning web task: {}", e);
         }
     }
+    if let Err(e) = spawner.spawn(http_overflow(stack)) {
+        error!("error spawning web overflow task: {}", e);
+    }
+    if let Err(e) = spawner.spawn(restarter()) {
+        error!("error spawning restart task: {}", e);
+    }
 }
 
 async fn setup_mode(
@@ -310,28 +537,36 @@ async fn setup_mode(
 
     spawner.spawn(net_task(runner)).ok();
 
-    let cmd_sender = CMD_CHANNEL.sender();
+    let service_state = mk_static!(
+        Mutex<CriticalSectionRawMutex, HttpServiceState>,
+        Mutex::new(HttpServiceState { storage, config })
+    );
 
     let http_server = mk_static!(
         weblite::server::Server::<HttpClientHandler>,
         weblite::server::Server::<_>::new(HttpClientHandler::new(
-            firmware::web::HttpServiceState {
-                storage,
-                config,
-                door_state: None,
-                lock_state: None,
-            },
-            cmd_sender,
+            service_state,
+            &COMMANDS,
             &STATE_PUBSUB,
+            &STATE_SNAPSHOT,
+            &FRIENDLY_NAMES,
         ))
     );
 
-    for _ in 0..4 {
+    let workers = web::set_connection_limit(config.http_max_connections.into());
+    resources::report(workers);
+    for _ in 0..workers {
         info!("starting a web server task");
         if let Err(e) = spawner.spawn(http_connection(stack, http_server)) {
             error!("error spawning web task: {}", e);
         }
     }
+    if let Err(e) = spawner.spawn(http_overflow(stack)) {
+        error!("error spawning web overflow task: {}", e);
+    }
+    if let Err(e) = spawner.spawn(restarter()) {
+        error!("error spawning restart task: {}", e);
+    }
 }
 
 #[embassy_executor::task]
@@ -370,11 +605,41 @@ async fn wifi_client(
     mut controller: WifiController<'static>,
     ssid: ConfigV1Value,
     pass: ConfigV1Value,
+    rssi_interval_secs: u16,
+    storage: Storage,
+    mut trial: NetworkTrial,
 ) -> ! {
     loop {
         if esp_radio::wifi::sta_state() == WifiStaState::Connected {
-            // wait until we're no longer connected
-            controller.wait_for_event(WifiEvent::StaDisconnected).await;
+            // wait until we're no longer connected, sampling the signal strength meanwhile
+            let mut scheduler = SamplingScheduler::new([rssi_interval_secs as u64 * 1000]);
+            loop {
+                let due = scheduler.next();
+                let sample = async move {
+                    match due {
+                        Some((sensor, due_ms)) => {
+                            Timer::at(Instant::from_millis(due_ms)).await;
+                            sensor
+                        }
+                        None => core::future::pending().await,
+                    }
+                };
+
+                let event = select::select(
+                    controller.wait_for_event(WifiEvent::StaDisconnected),
+                    sample,
+                )
+                .await;
+                match event {
+                    select::Either::First(()) => break,
+                    select::Either::Second(sensor) => {
+                        scheduler.sampled(sensor, Instant::now().as_millis());
+                        LINK_QUALITY.set_rssi(controller.rssi().ok().map(|rssi| rssi as i8));
+                    }
+                }
+            }
+            LINK_QUALITY.set_rssi(None);
+            health::set_wifi_connected(false);
             Timer::after(Duration::from_millis(5000)).await
         }
 
@@ -405,24 +670,82 @@ async fn wifi_client(
         match controller.connect_async().await {
             Ok(_) => {
                 info!("Wifi connected!");
+                health::set_wifi_connected(true);
                 LIGHT_UPDATE.signal(LightPattern::Solid(LightColor::amber()));
+
+                if trial.connected() {
+                    info!("new wifi settings work, discarding fallback config");
+                    let mut locked_storage = storage.lock().await;
+                    if let Err(e) = ConfigV1::discard_fallback(locked_storage.deref_mut()) {
+                        error!("failed to discard fallback config: {}", e);
+                    }
+                }
             }
             Err(e) => {
                 info!("Failed to connect to wifi: {:?}", e);
+                if trial.failed() {
+                    warn!("new wifi settings don't work, reverting to the previous config");
+                    let mut locked_storage = storage.lock().await;
+                    match ConfigV1::revert_to_fallback(locked_storage.deref_mut()) {
+                        Ok(config) => {
+                            warn!(
+                                "reverted to wifi network {}, rebooting",
+                                config.wifi_ssid.as_str()
+                            );
+                            web::drain(DRAIN_TIMEOUT).await;
+                            esp_hal::system::software_reset();
+                        }
+                        Err(e) => error!("failed to revert config: {}", e),
+                    }
+                }
                 Timer::after(Duration::from_millis(5000)).await
             }
         }
     }
 }
 
+fn reset_reason() -> ResetReason {
+    match rtc_cntl::reset_reason(Cpu::ProCpu) {
+        Some(SocResetReason::ChipPowerOn) => ResetReason::PowerOn,
+        Some(SocResetReason::CoreSw | SocResetReason::Cpu0Sw) => ResetReason::Software,
+        Some(
+            SocResetReason::CoreMwdt0
+            | SocResetReason::CoreMwdt1
+            | SocResetReason::CoreRtcWdt
+            | SocResetReason::Cpu0Mwdt0
+            | SocResetReason::Cpu0Mwdt1
+            | SocResetReason::Cpu0RtcWdt
+            | SocResetReason::SysRtcWdt
+            | SocResetReason::SysSuperWdt,
+        ) => ResetReason::Watchdog,
+        Some(SocResetReason::SysBrownOut) => ResetReason::Brownout,
+        Some(SocResetReason::CoreDeepSleep) => ResetReason::DeepSleep,
+        _ => ResetReason::Unknown,
+    }
+}
+
 #[embassy_executor::task]
-async fn mqtt_service(device_id: &'static [u8; 12], config: ConfigV1, stack: Stack<'static>) -> ! {
+async fn mqtt_service(
+    device_id: &'static [u8; 12],
+    config: ConfigV1,
+    stack: Stack<'static>,
+    boot_count: u32,
+) -> ! {
     let mut context = MQTTContext::new(
         device_id,
         config.device_name.as_str(),
         config.mqtt_user.as_str(),
         config.mqtt_pass.as_str(),
-    );
+    )
+    .with_reset_reason(reset_reason())
+    .with_boot_count(boot_count)
+    .with_link_quality(&LINK_QUALITY)
+    .with_friendly_names(&FRIENDLY_NAMES)
+    .with_power_fail(&POWER_FAIL);
+    #[cfg(feature = "distance-sensor")]
+    let mut context = context.with_position_sensor();
+    #[cfg(feature = "vehicle-sensor")]
+    let mut context = context.with_vehicle_sensor();
 
     let mqtt_ipaddr = match Ipv4Addr::from_str(config.mqtt_host.as_str()) {
         Ok(i) => i,
@@ -480,32 +803,41 @@ async fn mqtt_service(device_id: &'static [u8; 12], config: ConfigV1, stack: Sta
                         info!("TLS connection to MQTT");
 
                         LIGHT_UPDATE.signal(LightPattern::Solid(LightColor::green()));
+                        health::set_mqtt_connected(true);
                         if let Err(e) = context
                             .run(
                                 tls_conn,
-                                &CMD_CHANNEL.sender(),
-                                &mut STATE_PUBSUB.subscriber().unwrap(),
+                                &COMMANDS,
+                                &mut StateFeed::new(
+                                    STATE_PUBSUB.subscriber().unwrap(),
+                                    &STATE_SNAPSHOT,
+                                ),
+                                &SENSORS,
                             )
                             .await
                         {
                             error!("MQTT session error: {}", e);
                         }
+                        health::set_mqtt_connected(false);
                     }
                 }
             }
             false => {
                 info!("TCP connection to MQTT");
                 LIGHT_UPDATE.signal(LightPattern::Solid(LightColor::green()));
+                health::set_mqtt_connected(true);
                 if let Err(e) = context
                     .run(
                         conn,
-                        &CMD_CHANNEL.sender(),
-                        &mut STATE_PUBSUB.subscriber().unwrap(),
+                        &COMMANDS,
+                        &mut StateFeed::new(STATE_PUBSUB.subscriber().unwrap(), &STATE_SNAPSHOT),
+                        &SENSORS,
                     )
                     .await
                 {
                     error!("MQTT session error: {}", e);
                 }
+                health::set_mqtt_connected(false);
             }
         }
 
@@ -513,7 +845,65 @@ async fn mqtt_service(device_id: &'static [u8; 12], config: ConfigV1, stack: Sta
     }
 }
 
-#[embassy_executor::task(pool_size = 4)]
+#[embassy_executor::task]
+async fn udp_control(stack: Stack<'static>, key: ConfigV1Value, boot_id: u32) -> ! {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buf = [0u8; 4 * REQUEST_LEN];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buf = [0u8; 4 * REQUEST_LEN];
+    let mut packet = [0u8; 64];
+
+    let mut control = UdpControl::new(key.as_str().as_bytes(), boot_id);
+
+    stack.wait_config_up().await;
+
+    let mut sock = UdpSocket::new(stack, &mut rx_meta, &mut rx_buf, &mut tx_meta, &mut tx_buf);
+    if let Err(e) = sock.bind(UDP_CONTROL_PORT) {
+        loop {
+            // Never progress...
+            error!("failed to bind UDP control port: {}", e);
+            Timer::after(Duration::from_secs(3600)).await;
+        }
+    }
+    info!("UDP control listening on port {}", UDP_CONTROL_PORT);
+
+    loop {
+        let (len, peer) = match sock.recv_from(&mut packet).await {
+            Ok(r) => r,
+            Err(e) => {
+                error!("error receiving UDP control packet: {}", e);
+                continue;
+            }
+        };
+
+        let (counter, result) = match control.receive(&packet[..len]) {
+            (counter, Ok(UdpCommand::Lock)) => {
+                info!("received lock command via UDP");
+                COMMANDS.send(LockAction::Lock, CommandSource::Udp, Instant::now());
+                (counter, UdpResult::Ok)
+            }
+            (counter, Ok(UdpCommand::Unlock)) => {
+                info!("received unlock command via UDP");
+                COMMANDS.send(LockAction::Unlock, CommandSource::Udp, Instant::now());
+                (counter, UdpResult::Ok)
+            }
+            (counter, Err(result)) => {
+                warn!("rejected UDP control packet: {}", result);
+                VISITOR_DENIED.signal(());
+                (counter, result)
+            }
+        };
+
+        let Some(resp) = control.response(counter, result) else {
+            continue;
+        };
+        if let Err(e) = sock.send_to(&resp, peer).await {
+            error!("error sending UDP control response: {}", e);
+        }
+    }
+}
+
+#[embassy_executor::task(pool_size = HTTP_WORKERS)]
 async fn http_connection(
     stack: Stack<'static>,
     http_server: &'static weblite::server::Server<HttpClientHandler>,
@@ -539,17 +929,211 @@ async fn http_connection(
             continue;
         }
 
-        if let Err(e) = http_server.serve(&mut conn, http_buff.as_mut_slice()).await {
+        if web::draining() {
+            // Restarting. Turn the connection away rather than start a response that will be cut
+            // off.
+            if let Err(e) = web::reject_draining(&mut conn).await {
+                error!("error rejecting http connection while draining: {}", e);
+            }
+            conn.close();
+            continue;
+        }
+
+        web::worker_busy();
+        let result = http_server.serve(&mut conn, http_buff.as_mut_slice()).await;
+        web::worker_idle();
+
+        // Go straight back to accepting so a browser loading several assets isn't left waiting
+        // on a worker. Only back off when something went wrong.
+        if let Err(e) = result {
             error!("HTTP error: {}", e);
+            Timer::after(Duration::from_secs(5)).await;
         }
+    }
+}
 
-        Timer::after(Duration::from_secs(5)).await;
+/// Restarts the device when asked, once the web server has drained.
+#[embassy_executor::task]
+async fn restarter() -> ! {
+    loop {
+        web::RESTART.wait().await;
+        web::drain(DRAIN_TIMEOUT).await;
+        esp_hal::system::software_reset();
+    }
+}
+
+/// Listens only while every HTTP worker is busy and turns new connections away with a 503 so they
+/// don't sit in the backlog.
+#[embassy_executor::task]
+async fn http_overflow(stack: Stack<'static>) -> ! {
+    let mut tx_buf = [0u8; 256];
+    let mut rx_buf = [0u8; 256];
+
+    loop {
+        stack.wait_link_up().await;
+        stack.wait_config_up().await;
+
+        // Reset before checking, so a worker that frees up after the check still ends the wait
+        // for a connection below.
+        web::HTTP_AVAILABLE.reset();
+        if !web::workers_saturated() {
+            web::HTTP_SATURATED.wait().await;
+            continue;
+        }
+
+        let mut conn = TcpSocket::new(stack, rx_buf.as_mut_slice(), tx_buf.as_mut_slice());
+        let accept = select::select(
+            conn.accept(IpListenEndpoint {
+                addr: None,
+                port: 80,
+            }),
+            web::HTTP_AVAILABLE.wait(),
+        )
+        .await;
+
+        match accept {
+            select::Either::First(Ok(())) => {
+                if let Err(e) = web::reject_busy(&mut conn).await {
+                    error!("error rejecting busy http connection: {}", e);
+                }
+                conn.close();
+            }
+            select::Either::First(Err(e)) => {
+                error!("error accepting overflow http connection: {}", e);
+                Timer::after(Duration::from_secs(5)).await;
+            }
+            select::Either::Second(_) if conn.state() != State::Listen => {
+                // A worker is free again, but a client is part way through connecting to this
+                // socket and dropping it would reset them. Finish the handshake and turn them away
+                // like any other overflow connection.
+                if with_timeout(Duration::from_secs(5), conn.wait_write_ready())
+                    .await
+                    .is_ok()
+                {
+                    if let Err(e) = web::reject_busy(&mut conn).await {
+                        error!("error rejecting busy http connection: {}", e);
+                    }
+                }
+                conn.close();
+            }
+            select::Either::Second(_) => {
+                // A worker is free again and no client has started connecting here. Closing this
+                // socket stops it listening, so the next connection goes to the free worker.
+            }
+        }
+    }
+}
+
+/// Samples resource usage during soak tests and panics if it looks like something is leaking.
+/// The first sample, taken once the device has had time to connect and settle, is the baseline.
+#[cfg(feature = "soak")]
+#[embassy_executor::task]
+async fn soak_monitor() -> ! {
+    let mut detector = LeakDetector::new(SoakLimits {
+        heap_growth: 4 * 1024,
+        http_active: HTTP_WORKERS,
+        queue_depth: 2,
+    });
+
+    Timer::after(SOAK_WARMUP).await;
+    loop {
+        let sample = ResourceSample {
+            heap_used: esp_alloc::HEAP.used(),
+            http_active: web::active_workers(),
+            cmd_queue: COMMANDS.len(),
+            state_queue: STATE_PUBSUB.len(),
+        };
+
+        match detector.check(sample) {
+            Ok(None) => info!("soak baseline: {}", sample),
+            Ok(Some(delta)) => info!("soak sample: {}, delta: {}", sample, delta),
+            Err(e) => {
+                error!("soak check failed: {}, sample: {}", e, sample);
+                panic!("soak check failed");
+            }
+        }
+
+        Timer::after(SOAK_INTERVAL).await;
+    }
+}
+
+const DOOR_SENSOR: usize = 0;
+const VEHICLE_SENSOR: usize = 1;
+
+/// Samples every fitted distance sensor on its configured interval. Sensors that are not fitted,
+/// or whose interval is configured as 0, are never sampled.
+#[embassy_executor::task]
+async fn sensor_sampler(
+    mut door_sensor: Option<Hcsr04>,
+    mut vehicle_sensor: Option<Hcsr04>,
+    config: ConfigV1,
+) -> ! {
+    let mut scheduler = SamplingScheduler::new([
+        door_sensor
+            .as_ref()
+            .map_or(0, |_| config.position_interval_ms as u64),
+        vehicle_sensor
+            .as_ref()
+            .map_or(0, |_| config.vehicle_interval_ms as u64),
+    ]);
+
+    let mut door_filter = DistanceFilter::default();
+    let mut last_position = None;
+    let mut vehicle_filter = DistanceFilter::default();
+    let mut detector = PresenceDetector::new(config.vehicle_threshold_mm);
+
+    loop {
+        let Some((sensor, due_ms)) = scheduler.next() else {
+            info!("no distance sensors to sample");
+            loop {
+                Timer::after(Duration::from_secs(3600)).await;
+            }
+        };
+        Timer::at(Instant::from_millis(due_ms)).await;
+        scheduler.sampled(sensor, Instant::now().as_millis());
+
+        match sensor {
+            DOOR_SENSOR => {
+                let Some(hcsr04) = door_sensor.as_mut() else {
+                    continue;
+                };
+                let Some(echo_us) = hcsr04.measure().await else {
+                    warn!("no echo from distance sensor");
+                    continue;
+                };
+                let distance_mm = door_filter.push(echo_to_mm(echo_us));
+                distance::set_distance_mm(distance_mm);
+
+                if let Some(position) = distance::calibration().position(distance_mm) {
+                    if position_changed(last_position, position, config.position_delta) {
+                        info!("door position {}% ({}mm)", position, distance_mm);
+                        last_position = Some(position);
+                        SENSORS.report(SensorReading::DoorPosition(position));
+                    }
+                }
+            }
+            VEHICLE_SENSOR => {
+                let Some(hcsr04) = vehicle_sensor.as_mut() else {
+                    continue;
+                };
+                let Some(echo_us) = hcsr04.measure().await else {
+                    warn!("no echo from vehicle sensor");
+                    continue;
+                };
+                let distance_mm = vehicle_filter.push(echo_to_mm(echo_us));
+                if let Some(present) = detector.update(distance_mm) {
+                    info!("vehicle present: {} ({}mm)", present, distance_mm);
+                    SENSORS.report(SensorReading::VehiclePresent(present));
+                }
+            }
+            _ => {}
+        }
     }
 }
 
 #[embassy_executor::task]
 async fn door_service(
-    mut door: Door<'static, Output<'static>, Input<'static>, CriticalSectionRawMutex>,
+    mut door: Door<'static, Output<'static>, ReedPin, CriticalSectionRawMutex>,
 ) -> ! {
     loop {
         door.run().await;
@@ -580,17 +1164,154 @@ async fn factory_resetter(mut pin: Input<'static>, storage: Storage) -> ! {
 
                 {
                     let mut locked_storage = storage.lock().await;
-                    if let Err(e) = locked_storage.erase(0, 4096) {
+                    if let Err(e) =
+                        flash::erase(locked_storage.deref_mut(), 0, CONFIG_FLASH_END).await
+                    {
                         error!("failed to erase storage before reset: {}", e);
                     }
                 }
 
+                web::drain(DRAIN_TIMEOUT).await;
                 esp_hal::system::software_reset();
             }
         }
     }
 }
 
+/// Toggles the lock when the override button is held for `hold`. Shorter pushes are ignored, so a
+/// knock against the button doesn't unlock the door.
+#[cfg(feature = "override-button")]
+#[embassy_executor::task]
+async fn override_button(mut pin: Input<'static>, hold: Duration) -> ! {
+    loop {
+        pin.wait_for_low().await;
+        info!("override button pushed");
+
+        match select::select(pin.wait_for_high(), Timer::after(hold)).await {
+            select::Either::First(_) => {
+                info!("override button released early, ignoring");
+            }
+            select::Either::Second(_) => {
+                let lock_state = STATE_SNAPSHOT
+                    .try_get()
+                    .and_then(|snapshot| snapshot.lock_state);
+                let action = LockAction::toggle(lock_state);
+                info!("override button held, sending {}", action);
+                COMMANDS.send(action, CommandSource::Button, Instant::now());
+
+                // Wait for the release so a long hold toggles only once.
+                pin.wait_for_high().await;
+            }
+        }
+    }
+}
+
+/// Handles loss of mains power while stored charge keeps the device running: puts the lock in its
+/// power fail state, reports the device offline and stops serving web requests. If power comes
+/// back before the charge runs out, the device restarts.
+#[cfg(feature = "power-fail")]
+#[embassy_executor::task]
+async fn power_fail(mut pin: Input<'static>, lock: PowerFailLock) -> ! {
+    loop {
+        pin.wait_for_low().await;
+        warn!("mains power lost");
+
+        if let Some(action) = lock.action() {
+            info!("power failing, sending {}", action);
+            COMMANDS.send(action, CommandSource::PowerFail, Instant::now());
+        }
+        POWER_FAIL.signal(());
+        SYNC_COUNTERS.signal(());
+        web::drain(DRAIN_TIMEOUT).await;
+
+        pin.wait_for_high().await;
+        info!("mains power back, restarting");
+        web::RESTART.signal(());
+    }
+}
+
+/// Keeps the counters in RTC memory up to date with each state change. Changed counters are synced
+/// to flash every `COUNTERS_SYNC_INTERVAL`, or straight away when mains power is lost, so flash
+/// isn't written for every change.
+#[embassy_executor::task]
+async fn counters_sync(storage: Storage, mut counters: Counters) -> ! {
+    let Some(mut snapshots) = STATE_SNAPSHOT.receiver() else {
+        loop {
+            // Never progress...
+            error!("no state snapshot receiver left for the counters sync");
+            Timer::after(Duration::from_secs(3600)).await;
+        }
+    };
+    let mut synced = None;
+    let mut next_sync = Instant::now() + COUNTERS_SYNC_INTERVAL;
+
+    loop {
+        match select::select3(
+            snapshots.changed(),
+            Timer::at(next_sync),
+            SYNC_COUNTERS.wait(),
+        )
+        .await
+        {
+            select::Either3::First(snapshot) => {
+                counters.event_seq = snapshot.seq;
+                set_rtc_counters(&counters);
+                continue;
+            }
+            select::Either3::Second(()) => next_sync += COUNTERS_SYNC_INTERVAL,
+            select::Either3::Third(()) => {}
+        }
+
+        if synced == Some(counters) {
+            continue;
+        }
+        match counters.save(storage.lock().await.deref_mut()) {
+            Ok(()) => {
+                info!("counters synced to flash");
+                synced = Some(counters);
+            }
+            Err(e) => error!("error syncing counters: {}", e),
+        }
+    }
+}
+
+/// Shows the lock state on the LED for people at the door: red while locked, green while
+/// unlocked, and a quick red flash when a request from the door is refused.
+#[embassy_executor::task]
+async fn visitor_light() -> ! {
+    let Some(mut snapshots) = STATE_SNAPSHOT.receiver() else {
+        loop {
+            // Never progress...
+            error!("no state snapshot receiver left for the visitor light");
+            Timer::after(Duration::from_secs(3600)).await;
+        }
+    };
+    let mut lock_state = STATE_SNAPSHOT
+        .try_get()
+        .and_then(|snapshot| snapshot.lock_state);
+
+    loop {
+        VISITOR_LIGHT_UPDATE.signal(lock_state.map(|lock_state| match lock_state {
+            LockState::Locked | LockState::Locking => LightPattern::Solid(LightColor::red()),
+            LockState::Unlocked | LockState::Unlocking | LockState::Jammed => {
+                LightPattern::Solid(LightColor::green())
+            }
+        }));
+
+        match select::select(snapshots.changed(), VISITOR_DENIED.wait()).await {
+            select::Either::First(snapshot) => lock_state = snapshot.lock_state,
+            select::Either::Second(()) => {
+                VISITOR_LIGHT_UPDATE.signal(Some(LightPattern::Blink(
+                    LightColor::red(),
+                    Duration::from_millis(150),
+                    Duration::from_millis(150),
+                )));
+                Timer::after(VISITOR_DENIED_TIME).await;
+            }
+        }
+    }
+}
+
 #[embassy_executor::task]
 async fn blink(mut led: Light<'static>) -> ! {
     info!("initializing LED");
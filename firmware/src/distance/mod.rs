@@ -0,0 +1,63 @@
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use esp_hal::gpio::{Input, Output};
+
+use doorctrl::distance::Calibration;
+
+// The HC-SR04 gives up and drops its echo line after ~38ms when nothing is in range.
+const ECHO_TIMEOUT: Duration = Duration::from_millis(40);
+
+static DISTANCE_MM: Mutex<CriticalSectionRawMutex, Cell<Option<u16>>> = Mutex::new(Cell::new(None));
+static CALIBRATION: Mutex<CriticalSectionRawMutex, Cell<Calibration>> =
+    Mutex::new(Cell::new(Calibration {
+        open_mm: 0,
+        closed_mm: 0,
+    }));
+
+/// Latest filtered distance, if the sensor has produced a reading.
+pub fn distance_mm() -> Option<u16> {
+    DISTANCE_MM.lock(|d| d.get())
+}
+
+pub fn set_distance_mm(distance_mm: u16) {
+    DISTANCE_MM.lock(|d| d.set(Some(distance_mm)));
+}
+
+pub fn calibration() -> Calibration {
+    CALIBRATION.lock(|c| c.get())
+}
+
+pub fn set_calibration(calibration: Calibration) {
+    CALIBRATION.lock(|c| c.set(calibration));
+}
+
+/// An HC-SR04 ultrasonic sensor on a trigger and echo pin.
+pub struct Hcsr04 {
+    trig: Output<'static>,
+    echo: Input<'static>,
+}
+
+impl Hcsr04 {
+    pub fn new(trig: Output<'static>, echo: Input<'static>) -> Self {
+        Self { trig, echo }
+    }
+
+    /// Trigger the sensor and time its echo pulse in microseconds.
+    pub async fn measure(&mut self) -> Option<u32> {
+        self.trig.set_high();
+        Timer::after_micros(10).await;
+        self.trig.set_low();
+
+        with_timeout(ECHO_TIMEOUT, self.echo.wait_for_high())
+            .await
+            .ok()?;
+        let start = Instant::now();
+        with_timeout(ECHO_TIMEOUT, self.echo.wait_for_low())
+            .await
+            .ok()?;
+
+        Some(start.elapsed().as_micros() as u32)
+    }
+}
@@ -0,0 +1,24 @@
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+
+use doorctrl::config::ConfigV1;
+
+static WIFI_CONNECTED: Mutex<CriticalSectionRawMutex, Cell<bool>> = Mutex::new(Cell::new(false));
+static MQTT_CONNECTED: Mutex<CriticalSectionRawMutex, Cell<bool>> = Mutex::new(Cell::new(false));
+
+pub fn set_wifi_connected(connected: bool) {
+    WIFI_CONNECTED.lock(|c| c.set(connected));
+}
+
+pub fn set_mqtt_connected(connected: bool) {
+    MQTT_CONNECTED.lock(|c| c.set(connected));
+}
+
+/// Whether the device currently meets the health criteria selected in config.
+pub fn healthy(config: &ConfigV1) -> bool {
+    config.healthy(
+        WIFI_CONNECTED.lock(|c| c.get()),
+        MQTT_CONNECTED.lock(|c| c.get()),
+    )
+}
@@ -1,4 +1,9 @@
+// The ESP32-C3 has no atomic instructions, so state shared between tasks is kept in `Cell`s guarded
+// by critical section mutexes.
 #![no_std]
+pub mod distance;
+pub mod health;
+pub mod resources;
 pub mod web;
 pub mod ws2812;
 
@@ -0,0 +1,53 @@
+// Task and socket budget, fixed at build time.
+//
+// Set `DOORCTRL_HTTP_WORKERS` and `DOORCTRL_SOCKET_NUM` in the environment (or the `[env]` table in
+// `.cargo/config.toml`) to rebalance them without editing code, e.g. to free a socket for another
+// integration by running fewer HTTP workers.
+use defmt::info;
+
+/// Most tasks that can serve HTTP connections. The configured connection limit decides how many
+/// are started.
+pub const HTTP_WORKERS: usize = parse(option_env!("DOORCTRL_HTTP_WORKERS"), 4);
+
+/// Sockets the network stack is sized for.
+pub const SOCKET_NUM: usize = parse(option_env!("DOORCTRL_SOCKET_NUM"), 8);
+
+/// Sockets used by everything other than the HTTP workers: the HTTP overflow listener, MQTT, UDP
+/// control and DHCP.
+pub const RESERVED_SOCKETS: usize = 4;
+
+const _: () = assert!(HTTP_WORKERS > 0, "DOORCTRL_HTTP_WORKERS must be at least 1");
+const _: () = assert!(
+    SOCKET_NUM >= HTTP_WORKERS + RESERVED_SOCKETS,
+    "DOORCTRL_SOCKET_NUM must cover the HTTP workers and the reserved sockets"
+);
+
+/// Log how the socket budget is used with `http_workers` HTTP workers running.
+pub fn report(http_workers: usize) {
+    info!(
+        "sockets: {} of {} allocated ({} http workers, {} reserved)",
+        http_workers + RESERVED_SOCKETS,
+        SOCKET_NUM,
+        http_workers,
+        RESERVED_SOCKETS
+    );
+}
+
+// Parses a decimal build setting, falling back to `default` when unset. Evaluated at compile time,
+// so a malformed value fails the build.
+const fn parse(value: Option<&str>, default: usize) -> usize {
+    let Some(value) = value else {
+        return default;
+    };
+
+    let bytes = value.as_bytes();
+    assert!(!bytes.is_empty(), "empty build setting");
+    let mut result = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        assert!(bytes[i].is_ascii_digit(), "build setting is not a number");
+        result = result * 10 + (bytes[i] - b'0') as usize;
+        i += 1;
+    }
+    result
+}
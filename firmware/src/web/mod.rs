@@ -1,19 +1,38 @@
-use core::{ops::DerefMut, str};
+use core::{cell::Cell, ops::DerefMut, str};
 
 use defmt::{error, info, warn};
 use embassy_futures::select;
 use embassy_sync::{
-    blocking_mutex::raw::CriticalSectionRawMutex, channel::Sender, mutex::Mutex,
+    blocking_mutex::{self, raw::CriticalSectionRawMutex},
+    mutex::Mutex,
     pubsub::PubSubChannel,
+    signal::Signal,
+    watch::{self, Watch},
 };
-use embassy_time::{Duration, Timer};
+use embassy_time::{Duration, Instant, Timer};
 use embedded_io_async::{Read, Write};
 use esp_bootloader_esp_idf::partitions::FlashRegion;
-use esp_hal::system::software_reset;
 use esp_storage::FlashStorage;
 
+use crate::distance;
+use crate::health;
+use crate::resources::HTTP_WORKERS;
+use doorctrl::asset::{self, Asset};
+use doorctrl::command::{CommandQueue, CommandSource, LockAction, COMMAND_QUEUE_LEN};
 use doorctrl::config::{ConfigV1, ConfigV1Update};
-use doorctrl::state::{AnyState, DoorState, LockState};
+use doorctrl::hass::names::FriendlyNames;
+#[cfg(feature = "simulate")]
+use doorctrl::simulate::{DoorSimulator, SimulateRequest};
+use doorctrl::state::{
+    AnyState, DoorState, LockState, StateEvent, StateFeed, StateUpdate, StateWatch,
+};
+use doorctrl::url::percent_decode;
+use doorctrl::ws::{
+    CalibratePosition, ClientRequest, ClientSession, WS_CONFIG_UPDATE, WS_DOOR_AJAR,
+    WS_DOOR_CLOSED, WS_DOOR_OPEN, WS_LOCK_JAMMED, WS_LOCK_LOCK, WS_LOCK_LOCKING, WS_LOCK_UNLOCK,
+    WS_LOCK_UNLOCKING, WS_NOTIFICATION, WS_STATE_UPDATE, WS_TOPIC_CONFIG, WS_TOPIC_NOTIFICATION,
+    WS_TOPIC_STATE,
+};
 use weblite::{
     request::Request,
     response::{Responder, StatusCode},
@@ -22,33 +41,166 @@ use weblite::{
     websocket::{Websocket, WebsocketError},
 };
 
-const WS_STATE_UPDATE: u8 = 1;
-const WS_CONFIG_UPDATE: u8 = 2;
-const WS_NOTIFICATION: u8 = 3;
+// Largest configuration update a client can send in chunks.
+const CONFIG_UPDATE_LEN: usize = 2048;
+
+// Longest request path that can be routed, once decoded.
+const PATH_BUFFER_LEN: usize = 128;
+
+const HTTP_BUSY_RESPONSE: &[u8] = b"HTTP/1.1 503 Service Unavailable\r\n\
+Retry-After: 5\r\n\
+Content-Length: 0\r\n\
+Connection: close\r\n\r\n";
+
+// Load gauge shared between the HTTP workers and the overflow listener.
+static HTTP_ACTIVE: blocking_mutex::Mutex<CriticalSectionRawMutex, Cell<usize>> =
+    blocking_mutex::Mutex::new(Cell::new(0));
+static HTTP_BUSY: blocking_mutex::Mutex<CriticalSectionRawMutex, Cell<u32>> =
+    blocking_mutex::Mutex::new(Cell::new(0));
+static HTTP_LIMIT: blocking_mutex::Mutex<CriticalSectionRawMutex, Cell<usize>> =
+    blocking_mutex::Mutex::new(Cell::new(HTTP_WORKERS));
+pub static HTTP_SATURATED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+pub static HTTP_AVAILABLE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+// Set once the server starts draining for a restart. Each websocket holds a receiver so it can
+// close rather than be cut off.
+static HTTP_DRAIN: Watch<CriticalSectionRawMutex, (), HTTP_WORKERS> = Watch::new();
+// How often a drain checks whether the connections being served have finished.
+const DRAIN_POLL: Duration = Duration::from_millis(50);
+
+/// Asks the restart task to drain the server and restart the device.
+pub static RESTART: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+// Fakes the reed switches for automated tests. The door task reads its reeds through it.
+#[cfg(feature = "simulate")]
+pub static DOOR_SIMULATOR: DoorSimulator<CriticalSectionRawMutex> = DoorSimulator::new();
+
+/// Set how many connections are served at once, returning the number of workers to start. Limited
+/// to between 1 and `HTTP_WORKERS`.
+pub fn set_connection_limit(limit: usize) -> usize {
+    let limit = limit.clamp(1, HTTP_WORKERS);
+    HTTP_LIMIT.lock(|l| l.set(limit));
+    limit
+}
+
+/// Record that a worker has accepted a connection.
+pub fn worker_busy() {
+    let active = HTTP_ACTIVE.lock(|active| {
+        active.set(active.get() + 1);
+        active.get()
+    });
+    if active >= HTTP_LIMIT.lock(|limit| limit.get()) {
+        HTTP_SATURATED.signal(());
+    }
+}
+
+/// Record that a worker has finished with its connection.
+pub fn worker_idle() {
+    HTTP_ACTIVE.lock(|active| active.set(active.get().saturating_sub(1)));
+    HTTP_AVAILABLE.signal(());
+}
+
+/// Number of HTTP connections currently being served.
+pub fn active_workers() -> usize {
+    HTTP_ACTIVE.lock(|active| active.get())
+}
+
+pub fn workers_saturated() -> bool {
+    HTTP_ACTIVE.lock(|active| active.get()) >= HTTP_LIMIT.lock(|limit| limit.get())
+}
 
-// state update payloads
-const WS_LOCK_LOCK: u8 = 1;
-const WS_LOCK_UNLOCK: u8 = 2;
-const WS_DOOR_OPEN: u8 = 3;
-const WS_DOOR_CLOSED: u8 = 4;
+/// Stop serving new requests and close websockets, then wait up to `timeout` for the connections
+/// being served to finish. Called before restarting so responses aren't cut off part way.
+pub async fn drain(timeout: Duration) {
+    info!("draining http connections");
+    HTTP_DRAIN.sender().send(());
 
-const HTML_INDEX: &[u8] = include_bytes!("html/index.html");
+    let deadline = Instant::now() + timeout;
+    while active_workers() > 0 && Instant::now() < deadline {
+        Timer::after(DRAIN_POLL).await;
+    }
+
+    let active = active_workers();
+    if active > 0 {
+        warn!("{} http connections still open after draining", active);
+    }
+}
+
+pub fn draining() -> bool {
+    HTTP_DRAIN.try_get().is_some()
+}
+
+// Resolves once the server starts draining, or never if there are more websockets than receivers.
+async fn drained(
+    drain: &mut Option<watch::Receiver<'static, CriticalSectionRawMutex, (), HTTP_WORKERS>>,
+) {
+    match drain {
+        Some(drain) => drain.get().await,
+        None => core::future::pending().await,
+    }
+}
+
+/// Respond to a connection accepted while draining. The client is asked to retry once the device
+/// is back.
+pub async fn reject_draining<C: Write>(conn: &mut C) -> Result<(), C::Error> {
+    conn.write_all(HTTP_BUSY_RESPONSE).await?;
+    conn.flush().await
+}
+
+/// Respond to a connection that no worker is free to serve.
+pub async fn reject_busy<C: Write>(conn: &mut C) -> Result<(), C::Error> {
+    let count = HTTP_BUSY.lock(|busy| {
+        busy.set(busy.get().wrapping_add(1));
+        busy.get()
+    });
+    warn!(
+        "all http workers busy, rejecting connection ({} total)",
+        count
+    );
+
+    conn.write_all(HTTP_BUSY_RESPONSE).await?;
+    conn.flush().await
+}
+
+// plain text state payloads
+const TEXT_LOCKED: &[u8] = b"LOCKED";
+const TEXT_UNLOCKED: &[u8] = b"UNLOCKED";
+const TEXT_LOCKING: &[u8] = b"LOCKING";
+const TEXT_UNLOCKING: &[u8] = b"UNLOCKING";
+const TEXT_JAMMED: &[u8] = b"JAMMED";
+const TEXT_OPEN: &[u8] = b"OPEN";
+const TEXT_CLOSED: &[u8] = b"CLOSED";
+const TEXT_AJAR: &[u8] = b"AJAR";
+const TEXT_UNKNOWN: &[u8] = b"UNKNOWN";
+const TEXT_HEALTHY: &[u8] = b"OK";
+const TEXT_UNHEALTHY: &[u8] = b"UNHEALTHY";
+#[cfg(feature = "simulate")]
+const TEXT_SIMULATED: &[u8] = b"OK";
+
+const HTML_400: &[u8] = include_bytes!("html/400.html");
 const HTML_404: &[u8] = include_bytes!("html/404.html");
-const FAVICON: &[u8] = include_bytes!("html/favicon.ico");
+
+// Static files, served by path.
+const ASSETS: &[Asset] = &[
+    Asset::new("/", include_bytes!("html/index.html")),
+    Asset::new("/favicon.ico", include_bytes!("html/favicon.ico")),
+];
 
 type Storage = &'static Mutex<CriticalSectionRawMutex, FlashRegion<'static, FlashStorage<'static>>>;
 
 pub struct HttpServiceState {
     pub storage: Storage,
     pub config: ConfigV1,
-    pub door_state: Option<DoorState>,
-    pub lock_state: Option<LockState>,
 }
 
+pub type ServiceState = &'static Mutex<CriticalSectionRawMutex, HttpServiceState>;
+
 pub struct HttpClientHandler {
-    inner: Mutex<CriticalSectionRawMutex, HttpServiceState>,
-    cmd_channel: Sender<'static, CriticalSectionRawMutex, LockState, 2>,
-    state_updates: &'static PubSubChannel<CriticalSectionRawMutex, AnyState, 2, 6, 0>,
+    inner: ServiceState,
+    commands: &'static CommandQueue<CriticalSectionRawMutex, COMMAND_QUEUE_LEN>,
+    state_updates: &'static PubSubChannel<CriticalSectionRawMutex, StateUpdate, 2, 6, 0>,
+    state_snapshot: &'static StateWatch<CriticalSectionRawMutex>,
+    friendly_names: &'static FriendlyNames,
 }
 
 impl RequestHandler for HttpClientHandler {
@@ -57,19 +209,86 @@ impl RequestHandler for HttpClientHandler {
         req: Request<'buff>,
         resp: Responder<'buff, 'client, C>,
     ) -> Result<Option<Websocket<'client, C>>, HandlerError> {
-        match req.path {
-            "/" => {
+        // The query string isn't part of the route. Handlers that take parameters decode them
+        // from the query.
+        #[cfg_attr(not(feature = "simulate"), allow(unused_variables))]
+        let (raw_path, query) = req.path.split_once('?').unwrap_or((req.path, ""));
+        let mut path_buf = [0u8; PATH_BUFFER_LEN];
+        let path = match percent_decode(raw_path, &mut path_buf) {
+            Ok(path) => path,
+            Err(e) => {
+                warn!("unable to decode request path: {}", e);
+                resp.with_status(StatusCode::BadRequest)
+                    .await?
+                    .with_body(HTML_400)
+                    .await?;
+                return Ok(None);
+            }
+        };
+
+        if let Some(asset) = asset::find(ASSETS, path) {
+            resp.with_status(StatusCode::OK)
+                .await?
+                .with_body(asset.body)
+                .await?;
+            return Ok(None);
+        }
+
+        #[cfg(feature = "simulate")]
+        if path == "/api/debug/simulate" {
+            return self.simulate(query, resp).await;
+        }
+
+        match path {
+            "/state/lock" => {
+                let lock_state = self
+                    .state_snapshot
+                    .try_get()
+                    .and_then(|snapshot| snapshot.lock_state);
+                let body = match lock_state {
+                    Some(LockState::Locked) => TEXT_LOCKED,
+                    Some(LockState::Unlocked) => TEXT_UNLOCKED,
+                    Some(LockState::Locking) => TEXT_LOCKING,
+                    Some(LockState::Unlocking) => TEXT_UNLOCKING,
+                    Some(LockState::Jammed) => TEXT_JAMMED,
+                    None => TEXT_UNKNOWN,
+                };
+
                 resp.with_status(StatusCode::OK)
                     .await?
-                    .with_body(HTML_INDEX)
+                    .with_body(body)
                     .await?;
             }
-            "/favicon.ico" => {
+            "/state/door" => {
+                let door_state = self
+                    .state_snapshot
+                    .try_get()
+                    .and_then(|snapshot| snapshot.door_state);
+                let body = match door_state {
+                    Some(DoorState::Open) => TEXT_OPEN,
+                    Some(DoorState::Closed) => TEXT_CLOSED,
+                    Some(DoorState::Ajar) => TEXT_AJAR,
+                    None => TEXT_UNKNOWN,
+                };
+
                 resp.with_status(StatusCode::OK)
                     .await?
-                    .with_body(FAVICON)
+                    .with_body(body)
                     .await?;
             }
+            "/healthz" => {
+                if health::healthy(&self.inner.lock().await.config) {
+                    resp.with_status(StatusCode::OK)
+                        .await?
+                        .with_body(TEXT_HEALTHY)
+                        .await?;
+                } else {
+                    resp.with_status(StatusCode::ServiceUnavailable)
+                        .await?
+                        .with_body(TEXT_UNHEALTHY)
+                        .await?;
+                }
+            }
             "/ws" => {
                 return Ok(Some(resp.upgrade(req).await?));
             }
@@ -99,17 +318,64 @@ impl RequestHandler for HttpClientHandler {
 
 impl HttpClientHandler {
     pub fn new(
-        inner: HttpServiceState,
-        cmd_channel: Sender<'static, CriticalSectionRawMutex, LockState, 2>,
-        state_updates: &'static PubSubChannel<CriticalSectionRawMutex, AnyState, 2, 6, 0>,
+        inner: ServiceState,
+        commands: &'static CommandQueue<CriticalSectionRawMutex, COMMAND_QUEUE_LEN>,
+        state_updates: &'static PubSubChannel<CriticalSectionRawMutex, StateUpdate, 2, 6, 0>,
+        state_snapshot: &'static StateWatch<CriticalSectionRawMutex>,
+        friendly_names: &'static FriendlyNames,
     ) -> Self {
         Self {
-            inner: Mutex::new(inner),
-            cmd_channel,
+            inner,
+            commands,
             state_updates,
+            state_snapshot,
+            friendly_names,
         }
     }
 
+    /// Fake the reed switches, e.g. `/api/debug/simulate?door=closed&reed_bounce=5`.
+    #[cfg(feature = "simulate")]
+    async fn simulate<'client, 'buff, C: Read + Write + 'client>(
+        &self,
+        query: &str,
+        resp: Responder<'buff, 'client, C>,
+    ) -> Result<Option<Websocket<'client, C>>, HandlerError> {
+        let request = match SimulateRequest::from_query(query) {
+            Ok(request) => request,
+            Err(e) => {
+                warn!("invalid simulation request: {}", e);
+                resp.with_status(StatusCode::BadRequest)
+                    .await?
+                    .with_body(e.as_bytes())
+                    .await?;
+                return Ok(None);
+            }
+        };
+
+        if let Some(door_state) = request.door {
+            DOOR_SIMULATOR.set(door_state);
+        }
+
+        if request.reed_bounce > 0 {
+            let Some(door_state) = DOOR_SIMULATOR.door_state() else {
+                resp.with_status(StatusCode::BadRequest)
+                    .await?
+                    .with_body(b"reed_bounce needs a simulated door state")
+                    .await?;
+                return Ok(None);
+            };
+            DOOR_SIMULATOR.bounce(door_state, request.reed_bounce).await;
+        }
+
+        info!("simulated door state: {}", DOOR_SIMULATOR.door_state());
+        resp.with_status(StatusCode::OK)
+            .await?
+            .with_body(TEXT_SIMULATED)
+            .await?;
+
+        Ok(None)
+    }
+
     async fn send_config_via_ws<'a, C>(
         &self,
         socket: &mut Websocket<'a, C>,
@@ -153,12 +419,24 @@ impl HttpClientHandler {
             AnyState::LockState(LockState::Unlocked) => {
                 socket.send(&mut [WS_STATE_UPDATE, WS_LOCK_UNLOCK]).await
             }
+            AnyState::LockState(LockState::Locking) => {
+                socket.send(&mut [WS_STATE_UPDATE, WS_LOCK_LOCKING]).await
+            }
+            AnyState::LockState(LockState::Unlocking) => {
+                socket.send(&mut [WS_STATE_UPDATE, WS_LOCK_UNLOCKING]).await
+            }
+            AnyState::LockState(LockState::Jammed) => {
+                socket.send(&mut [WS_STATE_UPDATE, WS_LOCK_JAMMED]).await
+            }
             AnyState::DoorState(DoorState::Open) => {
                 socket.send(&mut [WS_STATE_UPDATE, WS_DOOR_OPEN]).await
             }
             AnyState::DoorState(DoorState::Closed) => {
                 socket.send(&mut [WS_STATE_UPDATE, WS_DOOR_CLOSED]).await
             }
+            AnyState::DoorState(DoorState::Ajar) => {
+                socket.send(&mut [WS_STATE_UPDATE, WS_DOOR_AJAR]).await
+            }
         } {
             error!("websocket: error writing to socket: {}", e);
             return Err(e);
@@ -185,32 +463,148 @@ impl HttpClientHandler {
         Ok(())
     }
 
-    async fn run_ws<'a, C>(
+    /// Send every state in the current snapshot.
+    async fn resync_via_ws<'a, C>(
         &self,
         socket: &mut Websocket<'a, C>,
-        buffer: &mut [u8],
+        state_feed: &mut StateFeed<'static, CriticalSectionRawMutex>,
+    ) -> Result<(), WebsocketError>
+    where
+        C: Read + Write,
+    {
+        let Some(snapshot) = state_feed.snapshot() else {
+            return Ok(());
+        };
+
+        for state in snapshot.states() {
+            self.send_state_via_ws(socket, state).await?;
+        }
+
+        Ok(())
+    }
+
+    // Apply a configuration update sent by a web client, restarting if the change needs it.
+    async fn apply_config_update<'a, C>(
+        &self,
+        socket: &mut Websocket<'a, C>,
+        json: &[u8],
+        topics: u8,
     ) -> Result<(), HandlerError>
     where
         C: Read + Write,
     {
-        // For the first client on the task, there will be states in the state sub queue.
-        // For subsequent clients, we will need to send retined states.
-        {
-            let inner = self.inner.lock().await;
-            if let Some(door_state) = inner.door_state {
-                self.send_state_via_ws(socket, AnyState::DoorState(door_state))
-                    .await?;
+        info!("{}", str::from_utf8(json).unwrap_or("not urf8"));
+        match serde_json_core::from_slice::<ConfigV1Update>(json) {
+            Ok((update, _)) => {
+                let mut inner = self.inner.lock().await;
+                let previous = inner.config;
+                inner.config.update(&update);
+                let restart = inner.config.needs_restart(&previous);
+                info!("config updated");
+                info!("device name: {}", inner.config.device_name.as_str());
+                info!("wifi_ssid: {}", inner.config.wifi_ssid.as_str());
+                info!("wifi_pass: {}", inner.config.wifi_pass.as_str());
+                info!("mqtt_host: {}", inner.config.mqtt_host.as_str());
+                info!("mqtt_user: {}", inner.config.mqtt_user.as_str());
+                info!("mqtt_pass: {}", inner.config.mqtt_pass.as_str());
+
+                let mut locked_storage = inner.storage.lock().await;
+                if inner.config.needs_fallback(&previous) {
+                    info!("wifi network changed, keeping previous config");
+                    if let Err(e) = previous.save_fallback(locked_storage.deref_mut()) {
+                        error!("failed to keep previous config: {}", e);
+                    }
+                }
+                match inner.config.save(locked_storage.deref_mut()) {
+                    Ok(()) if !restart => {
+                        info!("config saved. applying friendly names");
+                        self.friendly_names
+                            .set(inner.config.lock_name, inner.config.door_name);
+                        if topics & WS_TOPIC_NOTIFICATION != 0 {
+                            self.send_notification_via_ws(socket, "Config saved".as_bytes())
+                                .await?;
+                        }
+                    }
+                    Ok(()) => {
+                        info!("config saved. rebooting");
+                        if topics & WS_TOPIC_NOTIFICATION != 0 {
+                            self.send_notification_via_ws(
+                                socket,
+                                "Config saved, rebooting...".as_bytes(),
+                            )
+                            .await?;
+                        }
+
+                        // Restarting drains the server, so this websocket is closed along with
+                        // the others.
+                        RESTART.signal(());
+                    }
+                    Err(e) => {
+                        error!("failed to save config: {}", e);
+                        if topics & WS_TOPIC_NOTIFICATION != 0 {
+                            self.send_notification_via_ws(socket, e.as_bytes()).await?;
+                        }
+                    }
+                }
             }
-            if let Some(lock_state) = inner.lock_state {
-                self.send_state_via_ws(socket, AnyState::LockState(lock_state))
-                    .await?;
+            Err(e) => {
+                error!("received invalid data: {}", e);
             }
         }
 
-        self.send_config_via_ws(socket).await?;
+        Ok(())
+    }
 
-        let mut state_sub = match self.state_updates.subscriber() {
-            Ok(s) => s,
+    // Record the current distance reading as the door's fully open or closed position.
+    async fn calibrate<'a, C>(
+        &self,
+        socket: &mut Websocket<'a, C>,
+        position: CalibratePosition,
+        topics: u8,
+    ) -> Result<(), HandlerError>
+    where
+        C: Read + Write,
+    {
+        let notification = match distance::distance_mm() {
+            Some(distance_mm) => {
+                let mut inner = self.inner.lock().await;
+                match position {
+                    CalibratePosition::Open => inner.config.door_open_mm = distance_mm,
+                    CalibratePosition::Closed => inner.config.door_closed_mm = distance_mm,
+                }
+                distance::set_calibration(inner.config.calibration());
+                info!("calibrated {} at {}mm", position, distance_mm);
+
+                let mut locked_storage = inner.storage.lock().await;
+                match inner.config.save(locked_storage.deref_mut()) {
+                    Ok(()) => "Calibrated",
+                    Err(e) => {
+                        error!("failed to save calibration: {}", e);
+                        e
+                    }
+                }
+            }
+            None => "No distance reading to calibrate with",
+        };
+
+        if topics & WS_TOPIC_NOTIFICATION != 0 {
+            self.send_notification_via_ws(socket, notification.as_bytes())
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn run_ws<'a, C>(
+        &self,
+        socket: &mut Websocket<'a, C>,
+        buffer: &mut [u8],
+    ) -> Result<(), HandlerError>
+    where
+        C: Read + Write,
+    {
+        let mut state_feed = match self.state_updates.subscriber() {
+            Ok(s) => StateFeed::new(s, self.state_snapshot),
             Err(_) => {
                 return Err(HandlerError::CustomError(
                     "webseocket process upable to subscribe to state updates",
@@ -218,10 +612,24 @@ impl HttpClientHandler {
             }
         };
 
+        // Send the current states so the client doesn't have to wait for the next change. This
+        // happens after subscribing so no change can slip between the snapshot and the updates.
+        self.resync_via_ws(socket, &mut state_feed).await?;
+
+        self.send_config_via_ws(socket).await?;
+
+        let mut session = ClientSession::<CONFIG_UPDATE_LEN>::new();
+        let mut drain = HTTP_DRAIN.receiver();
         loop {
             info!("websocket: waiting for state update or data from client");
-            match select::select(socket.receive(buffer), state_sub.next_message_pure()).await {
-                select::Either::First(Ok(ws)) => {
+            match select::select3(
+                socket.receive(buffer),
+                state_feed.next(),
+                drained(&mut drain),
+            )
+            .await
+            {
+                select::Either3::First(Ok(ws)) => {
                     info!("websocket: processing client data");
 
                     if ws.opcode == 8 {
@@ -229,75 +637,65 @@ impl HttpClientHandler {
                         return Ok(());
                     }
 
-                    let data = &buffer[..ws.len];
-                    if data.len() < 2 {
-                        error!("websocket messages should have at least 2 bytes of data");
-                        return Err(HandlerError::WebsocketError(
-                            WebsocketError::InsufficientData(2),
-                        ));
-                    }
+                    let topics = session.topics();
+                    match session.receive(&buffer[..ws.len]) {
+                        Ok(Some(ClientRequest::Command(action))) => {
+                            self.commands
+                                .send(action, CommandSource::Web, Instant::now());
+                        }
+                        Ok(Some(ClientRequest::ConfigUpdate(json))) => {
+                            self.apply_config_update(socket, json, topics).await?;
+                        }
+                        Ok(Some(ClientRequest::Subscribed { added })) => {
+                            info!("websocket: subscribed to {=u8:b}", session.topics());
 
-                    match data[0] {
-                        WS_STATE_UPDATE => match data[1] {
-                            WS_LOCK_LOCK => self.cmd_channel.send(LockState::Locked).await,
-                            WS_LOCK_UNLOCK => self.cmd_channel.send(LockState::Unlocked).await,
-                            _ => warn!(
-                                "received unknown state update from websocket: {}",
-                                buffer[0]
-                            ),
-                        },
-                        WS_CONFIG_UPDATE => {
-                            info!("{}", str::from_utf8(&data[1..]).unwrap_or("not urf8"));
-                            match serde_json_core::from_slice::<ConfigV1Update>(&data[1..]) {
-                                Ok((update, _)) => {
-                                    let mut inner = self.inner.lock().await;
-                                    inner.config.update(&update);
-                                    info!("config updated");
-                                    info!("device name: {}", inner.config.device_name.as_str());
-                                    info!("wifi_ssid: {}", inner.config.wifi_ssid.as_str());
-                                    info!("wifi_pass: {}", inner.config.wifi_pass.as_str());
-                                    info!("mqtt_host: {}", inner.config.mqtt_host.as_str());
-                                    info!("mqtt_user: {}", inner.config.mqtt_user.as_str());
-                                    info!("mqtt_pass: {}", inner.config.mqtt_pass.as_str());
-
-                                    let mut locked_storage = inner.storage.lock().await;
-                                    match inner.config.save(locked_storage.deref_mut()) {
-                                        Ok(()) => {
-                                            info!("config saved. rebooting");
-                                            self.send_notification_via_ws(
-                                                socket,
-                                                "Config saved, rebooting...".as_bytes(),
-                                            )
-                                            .await?;
-
-                                            Timer::after(Duration::from_secs(1)).await;
-                                            software_reset();
-                                        }
-                                        Err(e) => {
-                                            error!("failed to save config: {}", e);
-                                            self.send_notification_via_ws(socket, e.as_bytes())
-                                                .await?;
-                                        }
-                                    }
-                                }
-                                Err(e) => {
-                                    error!("received invalid data: {}", e);
-                                }
+                            // Catch up on anything newly subscribed to, as on connecting.
+                            if added & WS_TOPIC_STATE != 0 {
+                                self.resync_via_ws(socket, &mut state_feed).await?;
                             }
+                            if added & WS_TOPIC_CONFIG != 0 {
+                                self.send_config_via_ws(socket).await?;
+                            }
+                        }
+                        Ok(Some(ClientRequest::Calibrate(position))) => {
+                            self.calibrate(socket, position, topics).await?;
                         }
-                        _ => {
-                            error!("websocket: received unknown payload type: {}", buffer[0]);
-                            return Err(HandlerError::CustomError("received unknown payload type"));
+                        Ok(Some(ClientRequest::Invalid(e))) => {
+                            warn!("websocket: ignoring message: {}", e);
+                            if topics & WS_TOPIC_NOTIFICATION != 0 {
+                                self.send_notification_via_ws(socket, e.as_bytes()).await?;
+                            }
+                        }
+                        Ok(None) => {}
+                        Err(e) => {
+                            error!("websocket: {}", e);
+                            return Err(HandlerError::CustomError(e));
                         }
                     }
                 }
-                select::Either::First(Err(e)) => {
+                select::Either3::First(Err(e)) => {
                     error!("websocket: error receiving websocket frame: {:?}", e);
                     return Err(HandlerError::WebsocketError(e));
                 }
-                select::Either::Second(state) => {
+                select::Either3::Second(_) if !session.subscribed(WS_TOPIC_STATE) => {
+                    // Not subscribed. The client is resynced if it subscribes again.
+                }
+                select::Either3::Second(StateEvent::Changed(update)) => {
                     info!("websocket: processing state update");
-                    self.send_state_via_ws(socket, state).await?;
+                    self.send_state_via_ws(socket, update.state).await?;
+                }
+                select::Either3::Second(StateEvent::Resync(snapshot)) => {
+                    for state in snapshot.states() {
+                        self.send_state_via_ws(socket, state).await?;
+                    }
+                }
+                select::Either3::Third(()) => {
+                    info!("websocket: closing for restart");
+                    if session.subscribed(WS_TOPIC_NOTIFICATION) {
+                        self.send_notification_via_ws(socket, "Restarting".as_bytes())
+                            .await?;
+                    }
+                    return Ok(());
                 }
             }
         }
@@ -9,6 +9,8 @@ use esp_hal::rmt::{Channel, PulseCode, Rmt, Tx, TxChannelConfig, TxChannelCreato
 use esp_hal::time::Rate;
 use esp_hal::Async;
 
+use doorctrl::status::{StatusArbiter, StatusBand};
+
 const BRG_MAX_NUM_OF_LEDS: usize = 256;
 const BRG_PACKET_SIZE: usize = 24;
 
@@ -143,9 +145,13 @@ impl<'a> WS2812B<'a> {
 
 const LIGHT_INTENSITY_DEFAULT: u8 = 32;
 
+// Device status patterns, shown unless a higher band is using the light.
 pub static LIGHT_UPDATE: Signal<CriticalSectionRawMutex, LightPattern> = Signal::new();
+// Visitor facing patterns, or None to hand the light back to the device status.
+pub static VISITOR_LIGHT_UPDATE: Signal<CriticalSectionRawMutex, Option<LightPattern>> =
+    Signal::new();
 
-#[derive(Default)]
+#[derive(Clone, Copy, Default)]
 pub struct LightColor {
     pub r: u8,
     pub g: u8,
@@ -189,6 +195,7 @@ impl LightColor {
     }
 }
 
+#[derive(Clone, Copy)]
 pub enum LightPattern {
     Off,
     Solid(LightColor),
@@ -199,20 +206,29 @@ pub enum LightPattern {
 
 pub struct Light<'a> {
     pub inner: WS2812B<'a>,
+    arbiter: StatusArbiter<LightPattern>,
 }
 
 impl<'a> Light<'a> {
+    pub fn new(inner: WS2812B<'a>) -> Self {
+        Self {
+            inner,
+            arbiter: StatusArbiter::new(),
+        }
+    }
+
     pub async fn update(update: LightPattern) {
         LIGHT_UPDATE.signal(update);
     }
 
     pub async fn run(&mut self, initial: LightPattern) -> ! {
+        self.arbiter.set(StatusBand::Diagnostic, Some(initial));
         let mut pattern = initial;
 
         loop {
             match self.do_pattern(pattern).await {
                 Ok(None) => {
-                    pattern = LIGHT_UPDATE.wait().await;
+                    pattern = self.next_update().await;
                     continue;
                 }
                 Ok(Some(next)) => {
@@ -272,13 +288,23 @@ impl<'a> Light<'a> {
         Ok(None)
     }
 
-    async fn wait(&self, dur: Duration) -> Option<LightPattern> {
-        match select(Timer::after(dur), LIGHT_UPDATE.wait()).await {
+    async fn wait(&mut self, dur: Duration) -> Option<LightPattern> {
+        match select(Timer::after(dur), self.next_update()).await {
             select::Either::First(_) => None,
             select::Either::Second(update) => Some(update),
         }
     }
 
+    // Wait for any band to change and return the pattern that should now be shown.
+    async fn next_update(&mut self) -> LightPattern {
+        match select(LIGHT_UPDATE.wait(), VISITOR_LIGHT_UPDATE.wait()).await {
+            select::Either::First(update) => self.arbiter.set(StatusBand::Diagnostic, Some(update)),
+            select::Either::Second(update) => self.arbiter.set(StatusBand::Visitor, update),
+        }
+
+        self.arbiter.current().unwrap_or(LightPattern::Off)
+    }
+
     pub async fn set_color(&mut self, color: &LightColor) -> Result<(), Error> {
         self.inner.set_colors(color.r, color.g, color.b).await
     }